@@ -0,0 +1,323 @@
+use std::fs::File;
+use std::io::{Read, Write, BufReader, BufWriter, ErrorKind};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use byteorder::{ReadBytesExt, WriteBytesExt, LittleEndian};
+
+use crate::packet::ClientVersion;
+
+// file: magic, format version:u16, client version:u8
+// record: kind:u8, timestamp:u64 (usecs since epoch), session:u32, then
+//   packet: flow:u8, len:u32, decrypted header+body
+//   seeds:  server len:u16, server seed, client len:u16, client seed
+const MAGIC: &[u8; 6] = b"DBCAP\0";
+const FORMAT_VERSION: u16 = 1;
+
+const RECORD_PACKET: u8 = 0;
+const RECORD_SEEDS: u8 = 1;
+
+#[derive(Debug)]
+pub enum CaptureError {
+    Io(std::io::Error),
+    BadMagic,
+    UnsupportedFormat(u16),
+    UnknownClientVersion(u8),
+    UnknownRecord(u8),
+    UnknownFlow(u8),
+}
+
+impl std::fmt::Display for CaptureError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CaptureError::Io(err) => write!(f, "io error: {}", err),
+            CaptureError::BadMagic => write!(f, "not a capture file"),
+            CaptureError::UnsupportedFormat(format) => write!(f, "unsupported capture format {}", format),
+            CaptureError::UnknownClientVersion(version) => write!(f, "unknown client version {}", version),
+            CaptureError::UnknownRecord(kind) => write!(f, "unknown record kind {}", kind),
+            CaptureError::UnknownFlow(flow) => write!(f, "unknown flow {}", flow),
+        }
+    }
+}
+
+impl From<std::io::Error> for CaptureError {
+    fn from(err: std::io::Error) -> CaptureError {
+        CaptureError::Io(err)
+    }
+}
+
+// which way a packet was going when it was seen
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Flow {
+    FromGamecube,
+    FromServer,
+    ToGamecube,
+    ToServer,
+}
+
+impl Flow {
+    fn to_u8(&self) -> u8 {
+        match self {
+            Flow::FromGamecube => 0,
+            Flow::FromServer => 1,
+            Flow::ToGamecube => 2,
+            Flow::ToServer => 3,
+        }
+    }
+
+    fn from_u8(flow: u8) -> Result<Flow, CaptureError> {
+        match flow {
+            0 => Ok(Flow::FromGamecube),
+            1 => Ok(Flow::FromServer),
+            2 => Ok(Flow::ToGamecube),
+            3 => Ok(Flow::ToServer),
+            _ => Err(CaptureError::UnknownFlow(flow)),
+        }
+    }
+}
+
+fn version_to_u8(version: ClientVersion) -> u8 {
+    match version {
+        ClientVersion::Dreamcast => 0,
+        ClientVersion::PC => 1,
+        ClientVersion::Gamecube => 2,
+        ClientVersion::BlueBurst => 3,
+    }
+}
+
+fn version_from_u8(version: u8) -> Result<ClientVersion, CaptureError> {
+    match version {
+        0 => Ok(ClientVersion::Dreamcast),
+        1 => Ok(ClientVersion::PC),
+        2 => Ok(ClientVersion::Gamecube),
+        3 => Ok(ClientVersion::BlueBurst),
+        _ => Err(CaptureError::UnknownClientVersion(version)),
+    }
+}
+
+// usecs since the epoch
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_micros() as u64)
+        .unwrap_or(0)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Record {
+    Packet {
+        timestamp: u64,
+        session: u32,
+        flow: Flow,
+        // decrypted, header included
+        data: Vec<u8>,
+    },
+    Seeds {
+        timestamp: u64,
+        session: u32,
+        server: Vec<u8>,
+        client: Vec<u8>,
+    },
+}
+
+// anything that wants to see the decrypted traffic as it goes through the proxy
+pub trait Tap: Send {
+    fn packet(&mut self, flow: Flow, data: &[u8]) -> std::io::Result<()>;
+
+    fn seeds(&mut self, _server: &[u8], _client: &[u8]) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+pub struct Recorder {
+    session: u32,
+    writer: Box<dyn Write + Send>,
+}
+
+impl Recorder {
+    pub fn create<P: AsRef<Path>>(path: P, session: u32, version: ClientVersion) -> std::io::Result<Recorder> {
+        Recorder::new(BufWriter::new(File::create(path)?), session, version)
+    }
+
+    pub fn new<W: Write + Send + 'static>(mut writer: W, session: u32, version: ClientVersion) -> std::io::Result<Recorder> {
+        writer.write_all(MAGIC)?;
+        writer.write_u16::<LittleEndian>(FORMAT_VERSION)?;
+        writer.write_u8(version_to_u8(version))?;
+        Ok(Recorder {
+            session: session,
+            writer: Box::new(writer),
+        })
+    }
+
+    fn record_header(&mut self, kind: u8) -> std::io::Result<()> {
+        self.writer.write_u8(kind)?;
+        self.writer.write_u64::<LittleEndian>(now())?;
+        self.writer.write_u32::<LittleEndian>(self.session)
+    }
+}
+
+impl Tap for Recorder {
+    fn packet(&mut self, flow: Flow, data: &[u8]) -> std::io::Result<()> {
+        self.record_header(RECORD_PACKET)?;
+        self.writer.write_u8(flow.to_u8())?;
+        self.writer.write_u32::<LittleEndian>(data.len() as u32)?;
+        self.writer.write_all(data)?;
+        self.writer.flush()
+    }
+
+    fn seeds(&mut self, server: &[u8], client: &[u8]) -> std::io::Result<()> {
+        self.record_header(RECORD_SEEDS)?;
+        self.writer.write_u16::<LittleEndian>(server.len() as u16)?;
+        self.writer.write_all(server)?;
+        self.writer.write_u16::<LittleEndian>(client.len() as u16)?;
+        self.writer.write_all(client)?;
+        self.writer.flush()
+    }
+}
+
+pub struct CaptureReader<R: Read> {
+    pub version: ClientVersion,
+    reader: R,
+}
+
+impl CaptureReader<BufReader<File>> {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<CaptureReader<BufReader<File>>, CaptureError> {
+        CaptureReader::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> CaptureReader<R> {
+    pub fn new(mut reader: R) -> Result<CaptureReader<R>, CaptureError> {
+        let mut magic = [0u8; 6];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(CaptureError::BadMagic);
+        }
+        let format = reader.read_u16::<LittleEndian>()?;
+        if format != FORMAT_VERSION {
+            return Err(CaptureError::UnsupportedFormat(format));
+        }
+        let version = version_from_u8(reader.read_u8()?)?;
+        Ok(CaptureReader {
+            version: version,
+            reader: reader,
+        })
+    }
+
+    fn read_bytes(&mut self, len: usize) -> Result<Vec<u8>, CaptureError> {
+        let mut buf = vec![0u8; len];
+        self.reader.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    // Ok(None) at a clean end of file
+    pub fn next_record(&mut self) -> Result<Option<Record>, CaptureError> {
+        let kind = match self.reader.read_u8() {
+            Ok(kind) => kind,
+            Err(ref err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        let timestamp = self.reader.read_u64::<LittleEndian>()?;
+        let session = self.reader.read_u32::<LittleEndian>()?;
+
+        match kind {
+            RECORD_PACKET => {
+                let flow = Flow::from_u8(self.reader.read_u8()?)?;
+                let len = self.reader.read_u32::<LittleEndian>()? as usize;
+                Ok(Some(Record::Packet {
+                    timestamp: timestamp,
+                    session: session,
+                    flow: flow,
+                    data: self.read_bytes(len)?,
+                }))
+            },
+            RECORD_SEEDS => {
+                let len = self.reader.read_u16::<LittleEndian>()? as usize;
+                let server = self.read_bytes(len)?;
+                let len = self.reader.read_u16::<LittleEndian>()? as usize;
+                let client = self.read_bytes(len)?;
+                Ok(Some(Record::Seeds {
+                    timestamp: timestamp,
+                    session: session,
+                    server: server,
+                    client: client,
+                }))
+            },
+            _ => Err(CaptureError::UnknownRecord(kind)),
+        }
+    }
+}
+
+impl<R: Read> Iterator for CaptureReader<R> {
+    type Item = Result<Record, CaptureError>;
+
+    fn next(&mut self) -> Option<Result<Record, CaptureError>> {
+        self.next_record().transpose()
+    }
+}
+
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    // lets a test get at what a tap wrote after handing it over
+    #[derive(Clone)]
+    pub struct SharedBuf(pub Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn write_then_read() {
+        let buf = SharedBuf(Arc::new(Mutex::new(Vec::new())));
+        let mut recorder = Recorder::new(buf.clone(), 7, ClientVersion::BlueBurst).unwrap();
+        recorder.seeds(&[1, 2, 3, 4], &[5, 6, 7, 8]).unwrap();
+        recorder.packet(Flow::FromServer, &[0x08, 0x00, 0x9A, 0x00, 0x01, 0x00, 0x00, 0x00]).unwrap();
+        recorder.packet(Flow::ToServer, &[0x0C, 0x00, 0x1D, 0x00, 0x00, 0x00, 0x00, 0x00, 1, 2, 3, 4]).unwrap();
+        drop(recorder);
+
+        let data = buf.0.lock().unwrap().clone();
+        let reader = CaptureReader::new(&data[..]).unwrap();
+        assert_eq!(reader.version, ClientVersion::BlueBurst);
+        let records = reader.collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(records.len(), 3);
+
+        match records[0] {
+            Record::Seeds { session, ref server, ref client, .. } => {
+                assert_eq!(session, 7);
+                assert_eq!(server, &vec![1, 2, 3, 4]);
+                assert_eq!(client, &vec![5, 6, 7, 8]);
+            },
+            ref r => panic!("expected seeds, got {:?}", r),
+        }
+        match records[2] {
+            Record::Packet { session, flow, ref data, .. } => {
+                assert_eq!(session, 7);
+                assert_eq!(flow, Flow::ToServer);
+                assert_eq!(data.len(), 12);
+            },
+            ref r => panic!("expected packet, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn truncated_record() {
+        let buf = SharedBuf(Arc::new(Mutex::new(Vec::new())));
+        let mut recorder = Recorder::new(buf.clone(), 1, ClientVersion::Gamecube).unwrap();
+        recorder.packet(Flow::FromGamecube, &[0x1D, 0x00, 0x04, 0x00]).unwrap();
+        drop(recorder);
+
+        let mut data = buf.0.lock().unwrap().clone();
+        data.pop();
+        let mut reader = CaptureReader::new(&data[..]).unwrap();
+        assert!(reader.next_record().is_err());
+    }
+}
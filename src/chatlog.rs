@@ -0,0 +1,133 @@
+// every chat line any session sees, one file per day (utc) in the configured directory:
+//   2025-10-18 12:34:56 [1] lobby 3 block 1 Asphodel (2687645420): hello
+use std::fs::{File, OpenOptions};
+use std::io::{Write, BufRead, BufReader};
+use std::path::{Path, PathBuf};
+
+use crate::packet::ChatMessage;
+use crate::proxy::Location;
+
+// days since the epoch to (year, month, day), howard hinnant's civil_from_days
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe/1460 + doe/36524 - doe/146096) / 365;
+    let doy = doe - (365*yoe + yoe/4 - yoe/100);
+    let mp = (5*doy + 2) / 153;
+    let day = doy - (153*mp + 2)/5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era*400 + if month <= 2 { 1 } else { 0 };
+    (year, month as u32, day as u32)
+}
+
+fn date(secs: u64) -> String {
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+fn time(secs: u64) -> String {
+    let secs = secs % 86400;
+    format!("{:02}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+pub struct ChatLog {
+    dir: PathBuf,
+    // date of the file that's open, a line from a new day starts a new file
+    day: String,
+    file: Option<File>,
+}
+
+impl ChatLog {
+    pub fn new<P: AsRef<Path>>(dir: P) -> ChatLog {
+        ChatLog {
+            dir: dir.as_ref().to_path_buf(),
+            day: String::new(),
+            file: None,
+        }
+    }
+
+    // secs since the epoch
+    pub fn write(&mut self, secs: u64, session: u32, location: &Location, chat: &ChatMessage) -> std::io::Result<()> {
+        let day = date(secs);
+        if self.file.is_none() || day != self.day {
+            std::fs::create_dir_all(&self.dir)?;
+            let path = self.dir.join(format!("chat-{}.log", day));
+            self.file = Some(OpenOptions::new().create(true).append(true).open(path)?);
+            self.day = day;
+        }
+
+        let line = format!("{} {} [{}] {} {} ({}): {}\n", self.day, time(secs), session, location, chat.name(), chat.guildcard, chat.message());
+        // one write per line so sessions sharing a file don't interleave mid line
+        self.file.as_mut().unwrap().write_all(line.as_bytes())
+    }
+}
+
+// every line in every log in `dir` containing `needle`, oldest first
+pub fn search<P: AsRef<Path>>(dir: P, needle: &str) -> std::io::Result<Vec<String>> {
+    let mut paths = std::fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .map(|name| name.starts_with("chat-") && name.ends_with(".log"))
+                .unwrap_or(false)
+        })
+        .collect::<Vec<_>>();
+    paths.sort();
+
+    let needle = needle.to_lowercase();
+    let mut found = Vec::new();
+    for path in paths {
+        for line in BufReader::new(File::open(path)?).lines() {
+            let line = line?;
+            if line.to_lowercase().contains(&needle) {
+                found.push(line);
+            }
+        }
+    }
+    Ok(found)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::{Packet, ClientVersion};
+
+    fn chat(name: &str, message: &str) -> ChatMessage {
+        let mut data = vec![0, 0, 0, 0, 0x39, 0x30, 0, 0];
+        data.extend(format!("{}\t\tE{}\0", name, message).bytes());
+        match Packet::parse(ClientVersion::Gamecube, 0x06, 0, &data) {
+            Packet::ChatMessage(chat) => chat,
+            pkt => panic!("parsed as {:?}", pkt),
+        }
+    }
+
+    #[test]
+    fn dates() {
+        assert_eq!(date(0), "1970-01-01");
+        assert_eq!(date(951782400), "2000-02-29");
+        assert_eq!(date(1760745600 + 86399), "2025-10-18");
+        assert_eq!(time(1760745600 + 3723), "01:02:03");
+    }
+
+    #[test]
+    fn rotates_and_searches() {
+        let dir = std::env::temp_dir().join(format!("darkbridge-chatlog-test-{}", std::process::id()));
+        let mut log = ChatLog::new(&dir);
+        let lobby = Location::Lobby { lobby: 2, block: 1 };
+        log.write(1760745600, 1, &lobby, &chat("Asphodel", "anyone for ep2?")).unwrap();
+        log.write(1760745600 + 60, 1, &Location::Game, &chat("Kireek", "no")).unwrap();
+        log.write(1760745600 + 86400, 2, &lobby, &chat("Asphodel", "EP2 now")).unwrap();
+
+        assert!(dir.join("chat-2025-10-18.log").exists());
+        assert!(dir.join("chat-2025-10-19.log").exists());
+        let found = search(&dir, "ep2").unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(found, vec![
+            "2025-10-18 00:00:00 [1] lobby 3 block 1 Asphodel (12345): anyone for ep2?".to_string(),
+            "2025-10-19 00:00:00 [2] lobby 3 block 1 Asphodel (12345): EP2 now".to_string(),
+        ]);
+    }
+}
@@ -1,27 +1,45 @@
-
-
+use std::io::Read;
+use std::path::Path;
+use byteorder::{ReadBytesExt, LittleEndian};
 
 const CIPHER_LEN: usize = 521;
+pub const BB_SEED_LEN: usize = 48;
+const BB_PARRAY_LEN: usize = 18;
+const BB_SBOX_LEN: usize = 1024;
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    Encrypt,
+    Decrypt,
+}
 
+// where a cipher was in its keystream, so a speculative decrypt can be undone
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Checkpoint {
+    generation: u64,
+    index: usize,
+}
 
-
-
-
-
-
-
-
-
-
-
-
+// gc/pc ciphers are a keystream so encrypt and decrypt are the same thing, bb
+// is a block cipher so each instance is built for one direction. buffers are
+// expected to already be a multiple of block_size.
+pub trait PacketCipher: Send {
+    fn apply(&mut self, buf: &mut [u8]);
+    fn block_size(&self) -> usize;
+    fn checkpoint(&self) -> Checkpoint;
+    fn rollback(&mut self, checkpoint: Checkpoint);
+}
 
 #[derive(Clone)]
 pub struct Cipher {
     seed: u32,
-    key: [u32; 521],
+    key: [u32; CIPHER_LEN],
     block_idx: usize,
+    // how many times the key has been remixed since init
+    generation: u64,
+    // key as it was before the last remix, only valid until it is rolled back into
+    prev_key: [u32; CIPHER_LEN],
+    prev_valid: bool,
 }
 
 
@@ -29,8 +47,11 @@ impl Cipher {
     pub fn new(seed: u32) -> Cipher {
         let mut cipher = Cipher {
             seed: seed,
-            key: [0; 521],
+            key: [0; CIPHER_LEN],
             block_idx: 0,
+            generation: 0,
+            prev_key: [0; CIPHER_LEN],
+            prev_valid: false,
         };
         cipher.init_keys();
         cipher
@@ -68,7 +89,7 @@ impl Cipher {
             for _ in 0..32 {
                 seed = seed.wrapping_mul(0x5D588B65);
                 basekey = basekey >> 1;
-                seed += 1;
+                seed = seed.wrapping_add(1);
                 basekey = if seed & 0x80000000 != 0 {
                     basekey | 0x80000000
                 }
@@ -104,24 +125,335 @@ impl Cipher {
         
     }
 
+    fn remix(&mut self) {
+        self.prev_key = self.key;
+        self.prev_valid = true;
+        self.mix_keys();
+        self.generation += 1;
+    }
+
     fn next_key(&mut self) -> [u8; 4]{
         self.block_idx += 1;
         if self.block_idx == CIPHER_LEN {
-            self.mix_keys();
+            self.remix();
         }
         let key = self.key[self.block_idx];
         [key as u8, (key >> 8) as u8, (key >> 16) as u8, (key >> 24) as u8]
     }
     
-    pub fn encrypt(&mut self, buf: &Vec<u8>) -> Vec<u8> {
-        let mut out = Vec::new();
-        for chunk in buf.chunks(4) {
+
+
+}
+
+impl PacketCipher for Cipher {
+    fn apply(&mut self, buf: &mut [u8]) {
+        for chunk in buf.chunks_mut(4) {
             let key = self.next_key();
-            for (i, c) in chunk.iter().enumerate() {
-                out.push(key[i] ^ c)
+            for (c, k) in chunk.iter_mut().zip(key.iter()) {
+                *c ^= k;
             }
         }
-        out
+    }
+
+    fn block_size(&self) -> usize {
+        4
+    }
+
+    fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            generation: self.generation,
+            index: self.block_idx,
+        }
+    }
+
+    fn rollback(&mut self, checkpoint: Checkpoint) {
+        if self.prev_valid && checkpoint.generation + 1 == self.generation {
+            self.key = self.prev_key;
+            self.prev_valid = false;
+            self.generation -= 1;
+        }
+        else if checkpoint.generation != self.generation {
+            // too far back to undo the remix, replay the keystream from the seed
+            *self = Cipher::new(self.seed);
+            while self.generation < checkpoint.generation {
+                self.remix();
+            }
+        }
+        self.block_idx = checkpoint.index;
     }
 }
 
+
+// the starting p-array and s-boxes every bb session key is mixed into. this comes
+// from the client so it is loaded from a file rather than shipped here.
+#[derive(Clone)]
+pub struct BBKeyFile {
+    parray: [u32; BB_PARRAY_LEN],
+    sbox: Vec<u32>,
+}
+
+impl BBKeyFile {
+    pub fn load<P: AsRef<Path>>(path: P) -> std::io::Result<BBKeyFile> {
+        let mut file = std::fs::File::open(path)?;
+        BBKeyFile::read(&mut file)
+    }
+
+    pub fn read<R: Read>(reader: &mut R) -> std::io::Result<BBKeyFile> {
+        let mut parray = [0u32; BB_PARRAY_LEN];
+        reader.read_u32_into::<LittleEndian>(&mut parray)?;
+        let mut sbox = vec![0u32; BB_SBOX_LEN];
+        reader.read_u32_into::<LittleEndian>(&mut sbox)?;
+        Ok(BBKeyFile {
+            parray: parray,
+            sbox: sbox,
+        })
+    }
+}
+
+// blue burst: a 4 round blowfish keyed from the 48 byte seed in the 0x03 packet
+#[derive(Clone)]
+pub struct BBCipher {
+    parray: [u32; BB_PARRAY_LEN],
+    sbox: Vec<u32>,
+    direction: Direction,
+}
+
+impl BBCipher {
+    // the seed is the whole 48 bytes from the 0x03 packet, anything shorter is a gc
+    // style seed that would only ever give a cipher the server doesn't use
+    pub fn new(keys: &BBKeyFile, seed: &[u8; BB_SEED_LEN], direction: Direction) -> BBCipher {
+        let mut cipher = BBCipher {
+            parray: keys.parray,
+            sbox: keys.sbox.clone(),
+            direction: direction,
+        };
+        cipher.init_keys(seed);
+        cipher
+    }
+
+    fn init_keys(&mut self, seed: &[u8; BB_SEED_LEN]) {
+        let mut seed = *seed;
+        for (i, s) in seed.iter_mut().enumerate() {
+            *s ^= [0x19, 0x16, 0x18][i % 3];
+        }
+
+        for (i, p) in self.parray.iter_mut().enumerate() {
+            let pos = (i * 4) % BB_SEED_LEN;
+            *p ^= u32::from_be_bytes([seed[pos], seed[pos + 1], seed[pos + 2], seed[pos + 3]]);
+        }
+
+        let (mut left, mut right) = (0, 0);
+        for i in (0..BB_PARRAY_LEN).step_by(2) {
+            let block = self.encrypt_block(left, right);
+            left = block.0;
+            right = block.1;
+            self.parray[i] = left;
+            self.parray[i + 1] = right;
+        }
+        for i in (0..BB_SBOX_LEN).step_by(2) {
+            let block = self.encrypt_block(left, right);
+            left = block.0;
+            right = block.1;
+            self.sbox[i] = left;
+            self.sbox[i + 1] = right;
+        }
+    }
+
+    fn round(&self, x: u32) -> u32 {
+        let a = self.sbox[(x >> 24) as usize];
+        let b = self.sbox[0x100 + ((x >> 16) & 0xFF) as usize];
+        let c = self.sbox[0x200 + ((x >> 8) & 0xFF) as usize];
+        let d = self.sbox[0x300 + (x & 0xFF) as usize];
+        (a.wrapping_add(b) ^ c).wrapping_add(d)
+    }
+
+    fn encrypt_block(&self, left: u32, right: u32) -> (u32, u32) {
+        let p = &self.parray;
+        let mut left = left ^ p[0];
+        let mut right = right ^ self.round(left) ^ p[1];
+        left ^= self.round(right) ^ p[2];
+        right ^= self.round(left) ^ p[3];
+        left ^= self.round(right) ^ p[4];
+        (right ^ p[5], left)
+    }
+
+    fn decrypt_block(&self, left: u32, right: u32) -> (u32, u32) {
+        let p = &self.parray;
+        let mut left = left ^ p[5];
+        let mut right = right ^ self.round(left) ^ p[4];
+        left ^= self.round(right) ^ p[3];
+        right ^= self.round(left) ^ p[2];
+        left ^= self.round(right) ^ p[1];
+        (right ^ p[0], left)
+    }
+
+}
+
+impl PacketCipher for BBCipher {
+    fn apply(&mut self, buf: &mut [u8]) {
+        for block in buf.chunks_exact_mut(8) {
+            let left = u32::from_le_bytes([block[0], block[1], block[2], block[3]]);
+            let right = u32::from_le_bytes([block[4], block[5], block[6], block[7]]);
+            let (left, right) = match self.direction {
+                Direction::Encrypt => self.encrypt_block(left, right),
+                Direction::Decrypt => self.decrypt_block(left, right),
+            };
+            block[..4].copy_from_slice(&left.to_le_bytes());
+            block[4..].copy_from_slice(&right.to_le_bytes());
+        }
+    }
+
+    fn block_size(&self) -> usize {
+        8
+    }
+
+    // apply never changes the cipher, each block only depends on the keys set up in
+    // new. so a header get_packet decrypted ahead of the body can just be decrypted
+    // again later and there is no position to save or restore
+    fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            generation: 0,
+            index: 0,
+        }
+    }
+
+    fn rollback(&mut self, _checkpoint: Checkpoint) {
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keystream(cipher: &mut Cipher, words: usize) -> Vec<u32> {
+        let mut buf = vec![0u8; words * 4];
+        cipher.apply(&mut buf);
+        buf.chunks(4)
+            .map(|c| u32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+            .collect()
+    }
+
+    // (seed, words 0..4, words 519..523, words 1040..1046), from a separate port of
+    // sylverant's CRYPT_GC_CreateKeys, CRYPT_GC_MixKeys and CRYPT_GC_GetNextKey rather
+    // than from this file. 519..523 crosses the first remix, 1040..1046 the second
+    const GC_VECTORS: [(u32, [u32; 4], [u32; 4], [u32; 6]); 5] = [
+        (0x00000000,
+         [0x273A199B, 0x475E3D06, 0x72090046, 0x3F091D65],
+         [0xAF5AF6A9, 0x3568A34C, 0x2F07F4A6, 0x4E27E112],
+         [0x56ECA22D, 0xEE317B93, 0x20DB61DA, 0xCB6E5AC1, 0x4E2FA451, 0x9DF311D2]),
+        (0x00000001,
+         [0x42160BCE, 0xB2A59CD2, 0x091F0D24, 0x2D8FC213],
+         [0xCB8C4857, 0x829F368F, 0x46A6C97B, 0xB23276AA],
+         [0x21DD169D, 0xEC193E23, 0xA3F62FC9, 0xD5B5A7C2, 0x57AC152C, 0xE9F20FA3]),
+        (0x12345678,
+         [0x0432E2C2, 0xDBCE2D4B, 0x9B6BF0B9, 0x2B92A50A],
+         [0xD59B581D, 0x09107725, 0x147A1C0F, 0x36A30C7E],
+         [0x9347D053, 0x4BCC11A4, 0x8AE7B7F4, 0x2132D440, 0xFCE81FC3, 0xFDB42F53]),
+        (0xDEADBEEF,
+         [0x31718613, 0x4D47EC31, 0x6CB680F0, 0xE070A8DD],
+         [0xB0F3BD61, 0xCB3B093F, 0xE31B09FB, 0x68C1005E],
+         [0xDF817DCF, 0x1F734B52, 0xCF1A36CF, 0x50BE454D, 0x1E5EE758, 0xD85DCD28]),
+        (0x6B8F3A21,
+         [0x67C56211, 0x37703A3E, 0xC34CEFC7, 0x0FA99A4B],
+         [0xC47D6D28, 0x3A0A639C, 0x4D7DA786, 0x0B6FAC9B],
+         [0xCE644417, 0x13AC15FB, 0x53FD83B7, 0x1AA05188, 0xACB942AC, 0x75534F2D]),
+    ];
+
+    #[test]
+    fn gc_keystream() {
+        for (seed, start, first_mix, second_mix) in GC_VECTORS.iter() {
+            let words = keystream(&mut Cipher::new(*seed), 1046);
+            assert_eq!(&words[0..4], start, "seed {:08X}", seed);
+            assert_eq!(&words[519..523], first_mix, "seed {:08X}", seed);
+            assert_eq!(&words[1040..1046], second_mix, "seed {:08X}", seed);
+        }
+    }
+
+    #[test]
+    fn gc_apply_is_chunk_independent() {
+        let mut whole = Cipher::new(0x12345678);
+        let mut pieces = Cipher::new(0x12345678);
+        let mut a = (0..0x1000).map(|i| i as u8).collect::<Vec<_>>();
+        let mut b = a.clone();
+        whole.apply(&mut a);
+        for chunk in b.chunks_mut(0x24) {
+            pieces.apply(chunk);
+        }
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn gc_rollback() {
+        // within a block, across one remix, and across several
+        for (skip, peek) in [(4, 4), (518, 8), (100, 3000)].iter() {
+            let mut cipher = Cipher::new(0xDEADBEEF);
+            let expected = keystream(&mut Cipher::new(0xDEADBEEF), skip + peek);
+
+            keystream(&mut cipher, *skip);
+            let checkpoint = cipher.checkpoint();
+            keystream(&mut cipher, *peek);
+            cipher.rollback(checkpoint);
+            assert_eq!(keystream(&mut cipher, *peek), &expected[*skip..], "skip {} peek {}", skip, peek);
+        }
+    }
+
+    fn bb_test_keys() -> BBKeyFile {
+        // not the real client table, just something with every bit in play
+        let mut x: u32 = 0x9E3779B9;
+        let mut data = Vec::new();
+        for _ in 0..(BB_PARRAY_LEN + BB_SBOX_LEN) {
+            x ^= x << 13;
+            x ^= x >> 17;
+            x ^= x << 5;
+            data.extend_from_slice(&x.to_le_bytes());
+        }
+        BBKeyFile::read(&mut &data[..]).unwrap()
+    }
+
+    // the same key table and seed through a separate port of sylverant's CRYPT_BB_CreateKeys
+    // and CRYPT_BB_Encrypt. the table is made up, so this checks the key schedule and the
+    // rounds but not the real client table
+    #[test]
+    fn bb_known_answer() {
+        let mut seed = [0u8; BB_SEED_LEN];
+        seed.iter_mut().enumerate().for_each(|(i, s)| *s = i as u8);
+        let mut buf = (0..16).collect::<Vec<u8>>();
+        BBCipher::new(&bb_test_keys(), &seed, Direction::Encrypt).apply(&mut buf);
+        assert_eq!(buf, vec![0x54, 0xE2, 0x9A, 0xF4, 0xA5, 0xD3, 0x87, 0x83, 0x02, 0x68, 0x95, 0x7F, 0xCF, 0x2B, 0x7F, 0xD8]);
+    }
+
+    #[test]
+    fn bb_round_trip() {
+        let keys = bb_test_keys();
+        let mut seed = [0u8; BB_SEED_LEN];
+        seed.iter_mut().enumerate().for_each(|(i, s)| *s = i as u8);
+        let plain = (0..16).collect::<Vec<u8>>();
+        let mut buf = plain.clone();
+        BBCipher::new(&keys, &seed, Direction::Encrypt).apply(&mut buf);
+        assert_ne!(buf[..8], plain[..8]);
+        assert_ne!(buf[8..], plain[8..]);
+        BBCipher::new(&keys, &seed, Direction::Decrypt).apply(&mut buf);
+        assert_eq!(buf, plain);
+
+        // a different seed is a different cipher
+        let mut other = plain.clone();
+        BBCipher::new(&keys, &[1; 48], Direction::Encrypt).apply(&mut other);
+        let mut first = plain.clone();
+        BBCipher::new(&keys, &seed, Direction::Encrypt).apply(&mut first);
+        assert_ne!(other, first);
+    }
+
+    // decrypting the same header twice has to give the same thing, which is what
+    // get_packet relies on in place of a rollback
+    #[test]
+    fn bb_apply_is_stateless() {
+        let mut cipher = BBCipher::new(&bb_test_keys(), &[7; 48], Direction::Decrypt);
+        let (mut a, mut b) = (vec![0x5Au8; 8], vec![0x5Au8; 8]);
+        let checkpoint = cipher.checkpoint();
+        cipher.apply(&mut a);
+        cipher.rollback(checkpoint);
+        cipher.apply(&mut b);
+        assert_eq!(a, b);
+    }
+}
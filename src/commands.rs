@@ -1,11 +1,14 @@
 use std::convert::TryFrom;
+use serde::Deserialize;
 
 use crate::filters::TargettedPacket;
 use crate::proxy::Proxy;
-use crate::proxy::{GameState, Position};
-use crate::packet::{Packet, RawData, PacketData};
+use crate::proxy::Position;
+use crate::packet::{Packet, RawData, ChatMessage};
 use crate::gamecommand::{GameCommand, GameCommandAction, ItemDrop};
 use crate::items::*;
+use crate::logging::{self, Directive, LogSpecError};
+use crate::chatlog;
 
 
 #[derive(Debug)]
@@ -14,6 +17,20 @@ pub enum CommandError {
     UnknownTarget(String),
     ItemParseError(ItemParseError),
     HexError(hex::FromHexError),
+    LogSpec(LogSpecError),
+}
+
+// what the player gets to see when a command goes wrong
+impl std::fmt::Display for CommandError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CommandError::UnknownCommand(cmd) => write!(f, "unknown command: {}", cmd),
+            CommandError::UnknownTarget(target) => write!(f, "unknown target: {}", target),
+            CommandError::ItemParseError(err) => write!(f, "bad item: {:?}", err),
+            CommandError::HexError(err) => write!(f, "bad hex: {:?}", err),
+            CommandError::LogSpec(err) => write!(f, "bad log spec: {}", err),
+        }
+    }
 }
 
 impl From<ItemParseError> for CommandError {
@@ -28,6 +45,12 @@ impl From<hex::FromHexError> for CommandError {
     }
 }
 
+impl From<LogSpecError> for CommandError {
+    fn from(err: LogSpecError) -> CommandError {
+        CommandError::LogSpec(err)
+    }
+}
+
 
 #[derive(Debug)]
 pub struct MakeItem {
@@ -265,23 +288,18 @@ impl MakeItem {
         })
     }
 
-    fn as_packet(&self, floor: u32, position: Position, item_id: u32) -> Packet {
-        Packet::GameCommand(GameCommand {
-            flag: 0,
-            client: 0,
-            unknown: 0,
-            cmd: GameCommandAction::ItemDrop(ItemDrop {
+    fn as_packet(&self, client: u8, floor: u32, position: Position, item_id: u32) -> Packet {
+        Packet::GameCommand(GameCommand::new(client, GameCommandAction::ItemDrop(ItemDrop {
                 floor: floor,
                 x: position.x,
                 z: position.z,
-                y: position.y,
                 item_row1: self.item.row1(),
                 item_row2: self.item.row2(),
                 item_row3: self.item.row3(),
                 itemdrop_id: item_id,
                 item_row4: self.item.row4(),
                 unknown: 2,
-            })})
+            })))
     }
 }
 
@@ -300,7 +318,11 @@ impl RawPacket {
             data.extend(hex::decode(value)?);
         }
 
-        let raw = RawData::parse(pkt_cmd[0], flag[0], &data);
+        let raw = RawData {
+            cmd: pkt_cmd[0] as u16,
+            flag: flag[0] as u32,
+            data: data,
+        };
 
 
         // TODO: proper index error checking
@@ -344,17 +366,41 @@ fn restore_parse(cmd: Vec<&str>) -> Result<Vec<ToolType>, CommandError> {
 }
 
 
+// `log` shows the filter, `log reset` goes back to the config's, anything else is a spec to apply on top
+#[derive(Debug)]
+pub enum LogCommand {
+    Show,
+    Reset,
+    Update(Vec<Directive>),
+}
+
+fn log_parse(cmd: Vec<&str>) -> Result<LogCommand, CommandError> {
+    match cmd.get(1) {
+        None => Ok(LogCommand::Show),
+        Some(&"reset") => Ok(LogCommand::Reset),
+        Some(_) => Ok(LogCommand::Update(logging::parse_spec(&cmd[1..].join(","))?)),
+    }
+}
+
+
 #[derive(Debug)]
 pub enum Command {
     MakeItem(MakeItem),
     Restore(Vec<ToolType>),
     RawPacket(RawPacket),
+    Log(LogCommand),
+    SearchChat(String),
+    Who,
+    // chat as the player, it goes to the server like anything they typed
+    Say(String),
 }
 
 impl Command {
     pub fn parse(data: String) -> Result<Command, CommandError> {
-        println!("parse! {:?}", data);
-        let split = data.split(" ").collect::<Vec<_>>();
+        debug!("parse: {:?}", data);
+        // everything but what gets said is case insensitive
+        let lower = data.to_ascii_lowercase();
+        let split = lower.split(" ").collect::<Vec<_>>();
 
         match split[0] {
             "weapon" => Ok(Command::MakeItem(MakeItem::parse_weapon(split)?)),
@@ -369,6 +415,10 @@ impl Command {
             "rawitem" => Ok(Command::MakeItem(MakeItem::parse_raw(split)?)),
             "raw" => Ok(Command::RawPacket(RawPacket::parse(split)?)),
             "restore" => Ok(Command::Restore(restore_parse(split)?)),
+            "log" => Ok(Command::Log(log_parse(split)?)),
+            "search" => Ok(Command::SearchChat(split[1..].join(" "))),
+            "who" => Ok(Command::Who),
+            "say" => Ok(Command::Say(data.splitn(2, ' ').nth(1).unwrap_or("").to_string())),
             _ => Err(CommandError::UnknownCommand(data))
         }
     }
@@ -376,6 +426,30 @@ impl Command {
 
 
 
+// how replies to commands show up on the gamecube, nothing of it goes to the server
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub enum ReplyStyle {
+    #[serde(rename = "chat")]
+    Chat,
+    #[serde(rename = "box")]
+    MessageBox,
+    #[serde(rename = "info")]
+    Info,
+}
+
+// who chat replies show up as said by
+const REPLY_NAME: &str = "darkbridge";
+// at most this many search results get sent back, the rest are only logged
+const MAX_SEARCH_REPLIES: usize = 5;
+
+pub fn reply(style: ReplyStyle, text: &str) -> TargettedPacket {
+    TargettedPacket::Client(Packet::ChatMessage(match style {
+        ReplyStyle::Chat => ChatMessage::to_player(REPLY_NAME, text),
+        ReplyStyle::MessageBox => ChatMessage::message_box(text),
+        ReplyStyle::Info => ChatMessage::info(text),
+    }))
+}
+
 #[derive(Debug)]
 pub struct CommandRunner {
     item_circle: Option<Vec<Command>>
@@ -391,13 +465,26 @@ impl CommandRunner {
         }
     }
 
+    // a line from chat or the command pipe, a bad one gets the player an error instead
+    pub fn run_line(&mut self, line: String, proxy: &mut Proxy) -> Vec<TargettedPacket> {
+        match Command::parse(line) {
+            Ok(cmd) => self.run(cmd, proxy),
+            Err(err) => {
+                warn!("[{}] command error: {:?}", proxy.session, err);
+                vec![reply(proxy.config.reply, &format!("\tC4{}", err))]
+            }
+        }
+    }
+
     pub fn run(&mut self, cmd: Command, proxy: &mut Proxy) -> Vec<TargettedPacket> {
+        let style = proxy.config.reply;
         match cmd {
             Command::MakeItem(makeitem) => {
-                let pkt = makeitem.as_packet(proxy.gamestate.floor, proxy.gamestate.position, proxy.gamestate.item_id());
+                let pkt = makeitem.as_packet(proxy.gamestate.self_client, proxy.gamestate.floor, proxy.gamestate.position, proxy.gamestate.item_id());
                 let mut result = Vec::new();
                 result.push(TargettedPacket::Client(pkt.clone()));
                 result.push(TargettedPacket::Server(pkt));
+                result.push(reply(style, "item dropped"));
                 result
             },
             Command::Restore(restore) => {
@@ -427,7 +514,7 @@ impl CommandRunner {
                     .collect::<Vec<_>>();
 
                 let total_size = restore_items.len() as f32;
-                restore_items
+                let mut result = restore_items
                     .into_iter()
                     .enumerate()
                     .flat_map(|(i, makeitem)| {
@@ -436,13 +523,59 @@ impl CommandRunner {
                         position.x += (2.0*std::f32::consts::PI*((i as f32)/total_size)).sin() * 12.0;
                         position.z += (2.0*std::f32::consts::PI*((i as f32)/total_size)).cos() * 12.0;
 
-                        let pkt = makeitem.as_packet(proxy.gamestate.floor, position, proxy.gamestate.item_id());
+                        let pkt = makeitem.as_packet(proxy.gamestate.self_client, proxy.gamestate.floor, position, proxy.gamestate.item_id());
                         vec![TargettedPacket::Client(pkt.clone()), TargettedPacket::Server(pkt)]
                     })
-                    .collect()
+                    .collect::<Vec<_>>();
+                result.push(reply(style, &format!("restored {} items", total_size as usize)));
+                result
             }
             Command::RawPacket(raw) => {
-                vec![raw.as_packet()]
+                vec![raw.as_packet(), reply(style, "packet sent")]
+            }
+            Command::Log(log) => {
+                match log {
+                    LogCommand::Show => {},
+                    LogCommand::Reset => logging::set(proxy.config.log.clone()),
+                    LogCommand::Update(directives) => logging::update(&directives),
+                }
+                let filter = logging::current();
+                info!("[{}] log filter: {}", proxy.session, filter);
+                vec![reply(style, &format!("log: {}", filter))]
+            }
+            Command::SearchChat(needle) => {
+                match proxy.config.chatlog_dir {
+                    Some(ref dir) => match chatlog::search(dir, &needle) {
+                        Ok(found) => {
+                            info!("[{}] {} chat lines matching {:?}", proxy.session, found.len(), needle);
+                            for line in found.iter() {
+                                info!("[{}] {}", proxy.session, line);
+                            }
+                            // the newest few, oldest first like the log
+                            std::iter::once(reply(style, &format!("{} lines matching {}", found.len(), needle)))
+                                .chain(found.iter().skip(found.len().saturating_sub(MAX_SEARCH_REPLIES)).map(|line| reply(style, line)))
+                                .collect()
+                        },
+                        Err(err) => {
+                            warn!("[{}] could not search chat logs: {:?}", proxy.session, err);
+                            vec![reply(style, "\tC4could not search chat logs")]
+                        },
+                    },
+                    None => vec![reply(style, "\tC4no chatlog_dir configured")],
+                }
+            }
+            Command::Who => {
+                let roster = &proxy.gamestate.roster;
+                info!("[{}] {} players in {}", proxy.session, roster.len(), proxy.gamestate.location);
+                std::iter::once(reply(style, &format!("{} players in {}", roster.len(), proxy.gamestate.location)))
+                    .chain(roster.players().map(|player| {
+                        info!("[{}] {}", proxy.session, player);
+                        reply(style, &player.to_string())
+                    }))
+                    .collect()
+            }
+            Command::Say(text) => {
+                vec![TargettedPacket::Server(Packet::ChatMessage(ChatMessage::from_player(&text)))]
             }
         }
     }
@@ -456,6 +589,7 @@ impl CommandRunner {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::proxy::GameState;
 
     #[test]
     fn weapon() {
@@ -470,7 +604,7 @@ mod tests {
             println!("{:08X}", makeitem.item.row1());
             println!("{:08X}", makeitem.item.row2());
             println!("{:08X}", makeitem.item.row3());
-            dbg!(makeitem.as_packet(gs.floor, gs.position, gs.item_id()));
+            dbg!(makeitem.as_packet(gs.self_client, gs.floor, gs.position, gs.item_id()));
         };
 
         let cmd = Command::parse("weapon notreal 100n 100a 100h".to_string());
@@ -488,6 +622,70 @@ mod tests {
         let mut gs = GameState::new();
     }
 
+    #[test]
+    fn errors_go_to_the_player() {
+        let mut config = crate::config::Config::for_tests(&[]);
+        config.reply = ReplyStyle::MessageBox;
+        let mut proxy = Proxy::offline(0, config).unwrap();
+        let mut runner = CommandRunner::new();
+
+        match runner.run_line("weapon notreal".to_string(), &mut proxy).as_slice() {
+            [TargettedPacket::Client(Packet::ChatMessage(msg))] => {
+                assert_eq!(msg.cmd, 0x01);
+                assert!(msg.message().starts_with("bad item"), "{}", msg.message());
+            },
+            pkts => panic!("got {:?}", pkts),
+        }
+
+        let pkts = runner.run_line("weapon df +9".to_string(), &mut proxy);
+        assert_eq!(pkts.len(), 3);
+        assert!(matches!(pkts[2], TargettedPacket::Client(Packet::ChatMessage(_))));
+    }
+
+    #[test]
+    fn who() {
+        let mut proxy = Proxy::offline(0, crate::config::Config::for_tests(&[])).unwrap();
+        proxy.gamestate.location = crate::proxy::Location::Game;
+        proxy.gamestate.roster.arrive(&[crate::lobby::PlayerHeader {
+            client_id: 1,
+            guildcard: 42000001,
+            name: "Kireek".to_string(),
+            class: Some(2),
+            section_id: Some(6),
+            level: Some(200),
+        }]);
+
+        let lines = CommandRunner::new().run_line("WHO".to_string(), &mut proxy)
+            .into_iter()
+            .map(|pkt| match pkt {
+                TargettedPacket::Client(Packet::ChatMessage(msg)) => msg.message(),
+                pkt => panic!("got {:?}", pkt),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(lines, vec!["1 players in game".to_string(), "1: Kireek (HUcast Redria lv200)".to_string()]);
+    }
+
+    #[test]
+    fn drops_come_from_us() {
+        let mut proxy = Proxy::offline(0, crate::config::Config::for_tests(&[])).unwrap();
+        proxy.gamestate.self_client = 3;
+        match CommandRunner::new().run_line("meseta 100".to_string(), &mut proxy).as_slice() {
+            [TargettedPacket::Client(Packet::GameCommand(client)), TargettedPacket::Server(Packet::GameCommand(server)), _] => {
+                assert_eq!(client.client, 3);
+                assert_eq!(server.client, 3);
+            },
+            pkts => panic!("got {:?}", pkts),
+        }
+    }
+
+    #[test]
+    fn say_keeps_case() {
+        match Command::parse("SAY Hello There".to_string()) {
+            Ok(Command::Say(text)) => assert_eq!(text, "Hello There"),
+            cmd => panic!("parsed as {:?}", cmd),
+        }
+    }
+
     #[test]
     fn mags() {
         let cmd = Command::parse("mag sato 5/145/50/0 leilla pilla twins".to_string());
@@ -504,7 +702,7 @@ mod tests {
             println!("{:08X}", makeitem.item.row2());
             println!("{:08X}", makeitem.item.row3());
             println!("{:08X}", makeitem.item.row4());
-            dbg!(makeitem.as_packet(gs.floor, gs.position, gs.item_id()));
+            dbg!(makeitem.as_packet(gs.self_client, gs.floor, gs.position, gs.item_id()));
         };
     }
 }
@@ -0,0 +1,288 @@
+use std::collections::HashMap;
+use std::net::{SocketAddr, Ipv4Addr, ToSocketAddrs};
+use std::path::PathBuf;
+
+use serde::Deserialize;
+
+use crate::packet::ClientVersion;
+use crate::logging::{LogFilter, LogSpecError};
+use crate::commands::ReplyStyle;
+
+const DEFAULT_CONFIG_PATH: &str = "darkbridge.toml";
+const PSOPORT: u16 = 9100;
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(std::io::Error),
+    Toml(toml::de::Error),
+    UnknownServer(String),
+    MissingValue(String),
+    UnknownFlag(String),
+    BadAddress(String),
+    UnknownVersion(String),
+    // dc and pc need the v2 cipher, which doesn't exist yet
+    UnsupportedVersion(ClientVersion),
+    UnknownReplyStyle(String),
+    LogSpec(LogSpecError),
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ConfigError::Io(err) => write!(f, "could not read config: {}", err),
+            ConfigError::Toml(err) => write!(f, "bad config file: {}", err),
+            ConfigError::UnknownServer(name) => write!(f, "unknown server: {}", name),
+            ConfigError::MissingValue(flag) => write!(f, "{} needs a value", flag),
+            ConfigError::UnknownFlag(flag) => write!(f, "unknown flag: {}\n{}", flag, usage()),
+            ConfigError::BadAddress(addr) => write!(f, "bad address: {}", addr),
+            ConfigError::UnknownVersion(version) => write!(f, "unknown client version: {}", version),
+            ConfigError::UnsupportedVersion(version) => write!(f, "{:?} clients aren't supported yet", version),
+            ConfigError::UnknownReplyStyle(style) => write!(f, "unknown reply style: {}", style),
+            ConfigError::LogSpec(err) => write!(f, "bad log spec: {}", err),
+        }
+    }
+}
+
+impl From<std::io::Error> for ConfigError {
+    fn from(err: std::io::Error) -> ConfigError {
+        ConfigError::Io(err)
+    }
+}
+
+impl From<toml::de::Error> for ConfigError {
+    fn from(err: toml::de::Error) -> ConfigError {
+        ConfigError::Toml(err)
+    }
+}
+
+impl From<LogSpecError> for ConfigError {
+    fn from(err: LogSpecError) -> ConfigError {
+        ConfigError::LogSpec(err)
+    }
+}
+
+fn parse_client_version(s: &str) -> Result<ClientVersion, ConfigError> {
+    match s {
+        "dc" => Ok(ClientVersion::Dreamcast),
+        "pc" => Ok(ClientVersion::PC),
+        "gc" => Ok(ClientVersion::Gamecube),
+        "bb" => Ok(ClientVersion::BlueBurst),
+        _ => Err(ConfigError::UnknownVersion(s.to_string())),
+    }
+}
+
+fn parse_reply_style(s: &str) -> Result<ReplyStyle, ConfigError> {
+    match s {
+        "chat" => Ok(ReplyStyle::Chat),
+        "box" => Ok(ReplyStyle::MessageBox),
+        "info" => Ok(ReplyStyle::Info),
+        _ => Err(ConfigError::UnknownReplyStyle(s.to_string())),
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ServerProfile {
+    pub host: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+}
+
+fn default_port() -> u16 {
+    PSOPORT
+}
+
+impl ServerProfile {
+    fn new(host: &str, port: u16) -> ServerProfile {
+        ServerProfile {
+            host: host.to_string(),
+            port: port,
+        }
+    }
+
+    fn parse(s: &str) -> Result<ServerProfile, ConfigError> {
+        let mut split = s.rsplitn(2, ':');
+        let port = split.next().ok_or(ConfigError::BadAddress(s.to_string()))?;
+        match split.next() {
+            Some(host) => Ok(ServerProfile::new(host, port.parse().map_err(|_| ConfigError::BadAddress(s.to_string()))?)),
+            None => Ok(ServerProfile::new(s, PSOPORT)),
+        }
+    }
+
+    pub fn addr(&self) -> std::io::Result<SocketAddr> {
+        (self.host.as_str(), self.port).to_socket_addrs()?
+            .next()
+            .ok_or(std::io::Error::new(std::io::ErrorKind::NotFound, format!("could not resolve {}", self.host)))
+    }
+}
+
+// what darkbridge.toml looks like on disk, everything optional so cli flags can fill the gaps
+#[derive(Debug, Default, Deserialize)]
+struct ConfigFile {
+    listen: Option<SocketAddr>,
+    redirect_ip: Option<Ipv4Addr>,
+    server: Option<String>,
+    upstream: Option<ServerProfile>,
+    client: Option<ClientVersion>,
+    bb_key_file: Option<PathBuf>,
+    capture_dir: Option<PathBuf>,
+    pcap_dir: Option<PathBuf>,
+    chatlog_dir: Option<PathBuf>,
+    reply: Option<ReplyStyle>,
+    log: Option<String>,
+    #[serde(default)]
+    servers: HashMap<String, ServerProfile>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub listen: SocketAddr,
+    // None means use whatever address the gamecube connected to
+    pub redirect_ip: Option<Ipv4Addr>,
+    pub upstream: ServerProfile,
+    pub client: ClientVersion,
+    pub bb_key_file: Option<PathBuf>,
+    // each session is recorded to its own file in here when set
+    pub capture_dir: Option<PathBuf>,
+    // same again as pcapng for wireshark
+    pub pcap_dir: Option<PathBuf>,
+    // chat from every session goes to daily logs in here
+    pub chatlog_dir: Option<PathBuf>,
+    // how command results and errors are shown to the player
+    pub reply: ReplyStyle,
+    // what gets logged at startup, and what `log reset` goes back to
+    pub log: LogFilter,
+    // run this capture through the filters instead of proxying
+    pub replay: Option<PathBuf>,
+    // write the wireshark dissector here instead of proxying
+    pub dissector: Option<PathBuf>,
+}
+
+fn default_servers() -> HashMap<String, ServerProfile> {
+    let mut servers = HashMap::new();
+    servers.insert("unseen".to_string(), ServerProfile::new("47.87.165.199", PSOPORT));
+    servers.insert("scht".to_string(), ServerProfile::new("149.56.167.128", PSOPORT));
+    servers.insert("elsewhere".to_string(), ServerProfile::new("45.33.31.247", PSOPORT));
+    servers
+}
+
+fn usage() -> String {
+    [
+        "usage: darkbridge [options]",
+        "  -c, --config <path>        config file (default: darkbridge.toml)",
+        "  -s, --server <name>        server profile to connect to",
+        "  -u, --upstream <host:port> upstream server, overrides --server",
+        "  -l, --listen <ip:port>     address to accept gamecubes on",
+        "  -r, --redirect-ip <ip>     address gamecubes are redirected back to",
+        "                             (default: the address the gamecube connected to)",
+        "  -v, --client <gc|bb>       client version being proxied (default: gc)",
+        "  -k, --bb-keys <path>       blue burst key file, required for bb",
+        "  -w, --capture <dir>        record decrypted sessions to capture files in <dir>",
+        "  -P, --pcap <dir>           export decrypted sessions as pcapng files in <dir>",
+        "  -C, --chatlog <dir>        log chat to a file per day in <dir>",
+        "  -R, --reply <style>        how command replies show up: chat, box or info",
+        "  -L, --log <spec>           what to log, e.g. info,proxy=debug,hide=60",
+        "  -p, --replay <file>        run a capture through the filters offline and exit",
+        "  -d, --dissector <file>     write a wireshark lua dissector and exit",
+    ].join("\n")
+}
+
+impl Config {
+    pub fn from_args<I: Iterator<Item = String>>(args: I) -> Result<Config, ConfigError> {
+        Config::build(args, true)
+    }
+
+    // only what's in `args`, a darkbridge.toml lying around can't change test results
+    #[cfg(test)]
+    pub fn for_tests(args: &[&str]) -> Config {
+        Config::build(args.iter().map(|arg| arg.to_string()), false).unwrap()
+    }
+
+    fn build<I: Iterator<Item = String>>(mut args: I, read_default: bool) -> Result<Config, ConfigError> {
+        let mut config_path = None;
+        let mut server = None;
+        let mut upstream = None;
+        let mut listen = None;
+        let mut redirect_ip = None;
+        let mut client = None;
+        let mut bb_key_file = None;
+        let mut capture_dir = None;
+        let mut pcap_dir = None;
+        let mut chatlog_dir = None;
+        let mut reply = None;
+        let mut log = None;
+        let mut replay = None;
+        let mut dissector = None;
+
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or(ConfigError::MissingValue(arg.clone()));
+            match arg.as_str() {
+                "-c" | "--config" => config_path = Some(PathBuf::from(value()?)),
+                "-s" | "--server" => server = Some(value()?),
+                "-u" | "--upstream" => upstream = Some(ServerProfile::parse(&value()?)?),
+                "-l" | "--listen" => {
+                    let v = value()?;
+                    listen = Some(v.parse().map_err(|_| ConfigError::BadAddress(v))?);
+                },
+                "-r" | "--redirect-ip" => {
+                    let v = value()?;
+                    redirect_ip = Some(v.parse().map_err(|_| ConfigError::BadAddress(v))?);
+                },
+                "-v" | "--client" => client = Some(parse_client_version(&value()?)?),
+                "-k" | "--bb-keys" => bb_key_file = Some(PathBuf::from(value()?)),
+                "-w" | "--capture" => capture_dir = Some(PathBuf::from(value()?)),
+                "-P" | "--pcap" => pcap_dir = Some(PathBuf::from(value()?)),
+                "-C" | "--chatlog" => chatlog_dir = Some(PathBuf::from(value()?)),
+                "-R" | "--reply" => reply = Some(parse_reply_style(&value()?)?),
+                "-L" | "--log" => log = Some(value()?),
+                "-p" | "--replay" => replay = Some(PathBuf::from(value()?)),
+                "-d" | "--dissector" => dissector = Some(PathBuf::from(value()?)),
+                "-h" | "--help" => {
+                    println!("{}", usage());
+                    std::process::exit(0);
+                },
+                _ => return Err(ConfigError::UnknownFlag(arg)),
+            }
+        }
+
+        let file = match config_path {
+            Some(path) => toml::from_str(&std::fs::read_to_string(path)?)?,
+            None if !read_default => ConfigFile::default(),
+            None => match std::fs::read_to_string(DEFAULT_CONFIG_PATH) {
+                Ok(s) => toml::from_str(&s)?,
+                Err(ref err) if err.kind() == std::io::ErrorKind::NotFound => ConfigFile::default(),
+                Err(err) => return Err(err.into()),
+            }
+        };
+
+        let mut servers = default_servers();
+        servers.extend(file.servers);
+
+        let upstream = match (upstream, server, file.upstream, file.server) {
+            (Some(upstream), _, _, _) => upstream,
+            (None, Some(name), _, _) => servers.remove(&name).ok_or(ConfigError::UnknownServer(name))?,
+            (None, None, Some(upstream), _) => upstream,
+            (None, None, None, Some(name)) => servers.remove(&name).ok_or(ConfigError::UnknownServer(name))?,
+            (None, None, None, None) => servers.remove("unseen").unwrap(),
+        };
+
+        let client = client.or(file.client).unwrap_or(ClientVersion::Gamecube);
+        if let ClientVersion::Dreamcast | ClientVersion::PC = client {
+            return Err(ConfigError::UnsupportedVersion(client));
+        }
+
+        Ok(Config {
+            listen: listen.or(file.listen).unwrap_or(SocketAddr::from((Ipv4Addr::new(0, 0, 0, 0), PSOPORT))),
+            redirect_ip: redirect_ip.or(file.redirect_ip),
+            upstream: upstream,
+            client: client,
+            bb_key_file: bb_key_file.or(file.bb_key_file),
+            capture_dir: capture_dir.or(file.capture_dir),
+            pcap_dir: pcap_dir.or(file.pcap_dir),
+            chatlog_dir: chatlog_dir.or(file.chatlog_dir),
+            reply: reply.or(file.reply).unwrap_or(ReplyStyle::Chat),
+            log: LogFilter::from_spec(log.or(file.log).as_deref().unwrap_or("info"))?,
+            replay: replay,
+            dissector: dissector,
+        })
+    }
+}
@@ -0,0 +1,130 @@
+use std::io::{Read, Write, ErrorKind};
+use std::net::{SocketAddr, Shutdown};
+use mio::{Registry, Token};
+use mio::net::TcpStream;
+
+// enough to always hold one full packet (len is a u16) with room to spare
+pub const MAX_BUFFER: usize = 0x20000;
+
+#[derive(Debug, PartialEq)]
+pub enum Fill {
+    // socket has nothing more for now
+    Drained,
+    // stopped reading because the read buffer is full, there is more waiting
+    Full,
+    // peer hung up
+    Closed,
+}
+
+// a socket plus what has been read from it but not parsed yet, and what has
+// been queued for it but not written yet
+#[derive(Debug)]
+pub struct Connection {
+    sock: Option<TcpStream>,
+    read_buf: Vec<u8>,
+    write_buf: Vec<u8>,
+}
+
+impl Connection {
+    pub fn new(sock: TcpStream) -> Connection {
+        Connection {
+            sock: Some(sock),
+            read_buf: Vec::new(),
+            write_buf: Vec::new(),
+        }
+    }
+
+    // nothing behind it, never has anything to read and whatever is queued just piles up
+    pub fn detached() -> Connection {
+        Connection {
+            sock: None,
+            read_buf: Vec::new(),
+            write_buf: Vec::new(),
+        }
+    }
+
+    pub fn sock(&self) -> Option<&TcpStream> {
+        self.sock.as_ref()
+    }
+
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.sock.as_ref()?.local_addr().ok()
+    }
+
+    pub fn register(&mut self, registry: &Registry, token: Token, interest: mio::Interest) -> std::io::Result<()> {
+        match self.sock {
+            Some(ref mut sock) => registry.register(sock, token, interest),
+            None => Ok(()),
+        }
+    }
+
+    pub fn deregister(&mut self, registry: &Registry) -> std::io::Result<()> {
+        match self.sock {
+            Some(ref mut sock) => registry.deregister(sock),
+            None => Ok(()),
+        }
+    }
+
+    pub fn shutdown(&self) -> std::io::Result<()> {
+        match self.sock {
+            Some(ref sock) => sock.shutdown(Shutdown::Both),
+            None => Ok(()),
+        }
+    }
+
+    pub fn fill(&mut self) -> std::io::Result<Fill> {
+        let sock = match self.sock {
+            Some(ref mut sock) => sock,
+            None => return Ok(Fill::Drained),
+        };
+        let mut buf = [0u8; 0x1000];
+        while self.read_buf.len() < MAX_BUFFER {
+            match sock.read(&mut buf) {
+                Ok(0) => return Ok(Fill::Closed),
+                Ok(len) => self.read_buf.extend_from_slice(&buf[..len]),
+                Err(ref err) if err.kind() == ErrorKind::WouldBlock => return Ok(Fill::Drained),
+                Err(ref err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(Fill::Full)
+    }
+
+    pub fn buffered(&self) -> &[u8] {
+        &self.read_buf
+    }
+
+    pub fn consume(&mut self, len: usize) -> Vec<u8> {
+        self.read_buf.drain(..len).collect()
+    }
+
+    // bytes queued but not yet accepted by the socket
+    pub fn pending(&self) -> usize {
+        self.write_buf.len()
+    }
+
+    pub fn queue(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        self.write_buf.extend_from_slice(buf);
+        self.flush()
+    }
+
+    // writes as much as the socket will take, the rest goes out on the next writable event
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        let sock = match self.sock {
+            Some(ref mut sock) => sock,
+            None => return Ok(()),
+        };
+        while !self.write_buf.is_empty() {
+            match sock.write(&self.write_buf) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(len) => {
+                    self.write_buf.drain(..len);
+                },
+                Err(ref err) if err.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(ref err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+}
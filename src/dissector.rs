@@ -0,0 +1,292 @@
+// writes a wireshark lua dissector from the field layouts in packet.rs and
+// gamecommand.rs, so pcap exports can be read without redoing them by hand
+
+use std::collections::HashSet;
+use std::fmt::Write;
+
+use crate::packet::{Packet, ClientVersion, Field, FieldKind};
+use crate::gamecommand::GameCommandAction;
+
+const PSOPORT: u16 = 9100;
+
+// header versions in the order the lua pref lists them
+const VERSIONS: [(ClientVersion, &str); 3] = [
+    (ClientVersion::Gamecube, "dc/gc"),
+    (ClientVersion::PC, "pc"),
+    (ClientVersion::BlueBurst, "bb"),
+];
+
+// (offset, size) of cmd, flag and len, mirrors ClientVersion::read_header
+fn header_fields(version: ClientVersion) -> [(usize, usize); 3] {
+    match version {
+        ClientVersion::Dreamcast | ClientVersion::Gamecube => [(0, 1), (1, 1), (2, 2)],
+        ClientVersion::PC => [(2, 1), (3, 1), (0, 2)],
+        ClientVersion::BlueBurst => [(2, 2), (4, 4), (0, 2)],
+    }
+}
+
+// where `version` is in the lua pref, dc goes in with gc
+fn version_index(version: ClientVersion) -> usize {
+    VERSIONS.iter().position(|(v, _)| header_fields(*v) == header_fields(version)).unwrap()
+}
+
+// EncryptionKeys -> encryption_keys
+fn snake_case(name: &str) -> String {
+    let mut out = String::new();
+    for (i, c) in name.chars().enumerate() {
+        if c.is_uppercase() && i > 0 {
+            out.push('_');
+        }
+        out.push(c.to_ascii_lowercase());
+    }
+    out
+}
+
+// ProtoField constructor, whether it goes in little endian, and its size (-1 for the rest of the body)
+fn proto_field(kind: FieldKind) -> (&'static str, bool, i64) {
+    let size = kind.size().map(|s| s as i64).unwrap_or(-1);
+    match kind {
+        FieldKind::U8 => ("uint8(%s, %s, base.HEX)", true, size),
+        FieldKind::U16 => ("uint16(%s, %s, base.DEC)", true, size),
+        FieldKind::U32 => ("uint32(%s, %s, base.HEX)", true, size),
+        FieldKind::U32BE => ("uint32(%s, %s, base.HEX)", false, size),
+        FieldKind::F32 => ("float(%s, %s)", true, size),
+        FieldKind::Ipv4 => ("ipv4(%s, %s)", false, size),
+        FieldKind::Str(_) | FieldKind::RestStr => ("string(%s, %s)", false, size),
+        FieldKind::Bytes(_) | FieldKind::Rest => ("bytes(%s, %s)", false, size),
+    }
+}
+
+// declares every field of `layout` and returns the lua table describing it
+fn layout_table(out: &mut String, declared: &mut HashSet<String>, name: &str, layout: &[Field], value_strings: Option<&str>) -> String {
+    let prefix = snake_case(name);
+    let mut entries = Vec::new();
+    for field in layout {
+        let var = format!("{}_{}", prefix, field.name);
+        let (ctor, little_endian, size) = proto_field(field.kind);
+        if declared.insert(var.clone()) {
+            let abbrev = format!("\"pso.{}.{}\"", prefix, field.name);
+            let label = format!("\"{}\"", field.name);
+            let mut ctor = ctor.replacen("%s", &abbrev, 1).replacen("%s", &label, 1);
+            // the subcommand byte gets named like cmd does
+            if let (Some(strings), "subcommand") = (value_strings, field.name) {
+                ctor = ctor.replace(")", &format!(", {})", strings));
+            }
+            writeln!(out, "f.{} = ProtoField.{}", var, ctor).unwrap();
+        }
+        entries.push(format!("{{ f.{}, {}, {} }}", var, size, little_endian));
+    }
+    format!("{{ {} }}", entries.join(", "))
+}
+
+pub fn generate() -> String {
+    let packets = Packet::layouts();
+    let subcommands = GameCommandAction::layouts();
+    let mut out = String::new();
+
+    writeln!(out, "-- phantasy star online dissector, generated by `darkbridge --dissector`").unwrap();
+    writeln!(out, "-- regenerate it rather than editing by hand").unwrap();
+    writeln!(out, "local pso = Proto(\"pso\", \"Phantasy Star Online\")").unwrap();
+    writeln!(out).unwrap();
+
+    writeln!(out, "local versions = {{").unwrap();
+    for (i, (_, name)) in VERSIONS.iter().enumerate() {
+        writeln!(out, "    {{ {}, \"{}\", {} }},", i + 1, name, i).unwrap();
+    }
+    writeln!(out, "}}").unwrap();
+    writeln!(out, "pso.prefs.version = Pref.enum(\"Client version\", 0, \"header layout of the client the capture is from\", versions, false)").unwrap();
+    writeln!(out).unwrap();
+
+    writeln!(out, "-- offset and size of cmd, flag and len in the header").unwrap();
+    writeln!(out, "local headers = {{").unwrap();
+    for (i, (version, _)) in VERSIONS.iter().enumerate() {
+        let [cmd, flag, len] = header_fields(*version);
+        writeln!(out, "    [{}] = {{ cmd = {{ {}, {} }}, flag = {{ {}, {} }}, len = {{ {}, {} }}, size = {}, align = {} }},",
+                 i, cmd.0, cmd.1, flag.0, flag.1, len.0, len.1, version.header_size(), version.alignment()).unwrap();
+    }
+    writeln!(out, "}}").unwrap();
+    writeln!(out).unwrap();
+
+    writeln!(out, "local commands = {{").unwrap();
+    for (cmd, name, _) in packets.iter() {
+        writeln!(out, "    [0x{:02X}] = \"{}\",", cmd, name).unwrap();
+    }
+    writeln!(out, "}}").unwrap();
+    writeln!(out, "local subcommands = {{").unwrap();
+    for (cmd, _, name, _) in subcommands.iter().filter(|(_, version, _, _)| version.is_none()) {
+        writeln!(out, "    [0x{:02X}] = \"{}\",", cmd, name).unwrap();
+    }
+    writeln!(out, "}}").unwrap();
+    writeln!(out, "-- only a subcommand when the capture is from that version, {{ version, name }}").unwrap();
+    writeln!(out, "local version_subcommands = {{").unwrap();
+    for (cmd, version, name, _) in subcommands.iter() {
+        if let Some(version) = version {
+            writeln!(out, "    [0x{:02X}] = {{ {}, \"{}\" }},", cmd, version_index(*version), name).unwrap();
+        }
+    }
+    writeln!(out, "}}").unwrap();
+    writeln!(out).unwrap();
+
+    writeln!(out, "local f = pso.fields").unwrap();
+    writeln!(out, "f.cmd = ProtoField.uint16(\"pso.cmd\", \"cmd\", base.HEX, commands)").unwrap();
+    writeln!(out, "f.flag = ProtoField.uint32(\"pso.flag\", \"flag\", base.HEX)").unwrap();
+    writeln!(out, "f.len = ProtoField.uint16(\"pso.len\", \"len\", base.DEC)").unwrap();
+
+    let mut declared = HashSet::new();
+    let mut layouts = Vec::new();
+    let mut game_commands = Vec::new();
+    for (cmd, name, layout) in packets.iter() {
+        layouts.push((*cmd, layout_table(&mut out, &mut declared, name, layout, Some("subcommands"))));
+        if name.starts_with("GameCommand") {
+            game_commands.push(*cmd);
+        }
+    }
+    let mut sublayouts = Vec::new();
+    for (cmd, _, name, layout) in subcommands.iter() {
+        sublayouts.push((*cmd, layout_table(&mut out, &mut declared, name, layout, None)));
+    }
+    writeln!(out).unwrap();
+
+    writeln!(out, "-- {{ field, size (-1 for the rest), little endian }}").unwrap();
+    writeln!(out, "local layouts = {{").unwrap();
+    for (cmd, table) in layouts {
+        writeln!(out, "    [0x{:02X}] = {},", cmd, table).unwrap();
+    }
+    writeln!(out, "}}").unwrap();
+    writeln!(out, "local sublayouts = {{").unwrap();
+    for (cmd, table) in sublayouts {
+        writeln!(out, "    [0x{:02X}] = {},", cmd, table).unwrap();
+    }
+    writeln!(out, "}}").unwrap();
+    writeln!(out, "local game_commands = {{").unwrap();
+    for cmd in game_commands {
+        writeln!(out, "    [0x{:02X}] = true,", cmd).unwrap();
+    }
+    writeln!(out, "}}").unwrap();
+    writeln!(out).unwrap();
+
+    out.push_str(DISSECTOR_BODY);
+    writeln!(out, "DissectorTable.get(\"tcp.port\"):add({}, pso)", PSOPORT).unwrap();
+    out
+}
+
+const DISSECTOR_BODY: &str = r#"local function dissect_fields(buf, tree, layout)
+    local offset = 0
+    for _, entry in ipairs(layout) do
+        local field, size, le = entry[1], entry[2], entry[3]
+        if size < 0 then
+            size = buf:len() - offset
+        end
+        if size <= 0 or buf:len() < offset + size then
+            return offset
+        end
+        if le then
+            tree:add_le(field, buf(offset, size))
+        else
+            tree:add(field, buf(offset, size))
+        end
+        offset = offset + size
+    end
+    return offset
+end
+
+function pso.dissector(buf, pinfo, tree)
+    local header = headers[pso.prefs.version]
+    local names = {}
+    local offset = 0
+    pinfo.cols.protocol = "PSO"
+
+    while offset < buf:len() do
+        if buf:len() - offset < header.size then
+            pinfo.desegment_offset = offset
+            pinfo.desegment_len = DESEGMENT_ONE_MORE_SEGMENT
+            return buf:len()
+        end
+
+        local cmd = buf(offset + header.cmd[1], header.cmd[2]):le_uint()
+        local flag = buf(offset + header.flag[1], header.flag[2]):le_uint()
+        local len = buf(offset + header.len[1], header.len[2]):le_uint()
+        if len < header.size then
+            return offset
+        end
+        -- gc counts padding in len, bb doesn't but still pads
+        local wire_len = math.floor((len + header.align - 1) / header.align) * header.align
+        if buf:len() - offset < wire_len then
+            pinfo.desegment_offset = offset
+            pinfo.desegment_len = wire_len - (buf:len() - offset)
+            return buf:len()
+        end
+
+        local name = commands[cmd] or string.format("0x%02X", cmd)
+        local pkt = tree:add(pso, buf(offset, wire_len), name)
+        pkt:add(f.cmd, buf(offset + header.cmd[1], header.cmd[2]), cmd)
+        pkt:add(f.flag, buf(offset + header.flag[1], header.flag[2]), flag)
+        pkt:add(f.len, buf(offset + header.len[1], header.len[2]), len)
+
+        if len > header.size and layouts[cmd] then
+            local body = buf(offset + header.size, len - header.size):tvb()
+            local used = dissect_fields(body, pkt, layouts[cmd])
+            if game_commands[cmd] and used > 0 and body:len() > used then
+                local subcmd = body(0, 1):uint()
+                local subname = subcommands[subcmd]
+                local only = version_subcommands[subcmd]
+                if only and only[1] == pso.prefs.version then
+                    subname = only[2]
+                end
+                local known = subname ~= nil
+                subname = subname or string.format("0x%02X", subcmd)
+                name = name .. " " .. subname
+                if known and sublayouts[subcmd] then
+                    local sub = pkt:add(pso, body(used), subname)
+                    dissect_fields(body(used):tvb(), sub, sublayouts[subcmd])
+                end
+            end
+        end
+
+        table.insert(names, name)
+        offset = offset + wire_len
+    end
+
+    pinfo.cols.info = table.concat(names, ", ")
+    return offset
+end
+
+"#;
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gamecommand::GameCommand;
+    use crate::packet::PacketData;
+
+    #[test]
+    fn covers_every_layout() {
+        let lua = generate();
+        for (cmd, name, layout) in Packet::layouts().iter().chain(GameCommandAction::layouts().iter().map(|(c, _, n, l)| (*c as u16, *n, *l)).collect::<Vec<_>>().iter()) {
+            assert!(lua.contains(&format!("[0x{:02X}] = \"{}\"", cmd, name)) || lua.contains(&format!("\"{}\" }}", name)), "{:02X} {}", cmd, name);
+            for field in layout.iter() {
+                assert!(lua.contains(&format!("\"pso.{}.{}\"", snake_case(name), field.name)), "{}.{}", name, field.name);
+            }
+        }
+        // a gc 0xBE isn't CreateItemBB
+        assert!(!lua.contains("[0xBE] = \"CreateItemBB\""));
+        assert!(lua.contains("[0xBE] = { 2, \"CreateItemBB\" }"));
+        for cmd in [0x60, 0x62, 0x6C, 0x6D].iter() {
+            assert!(lua.contains(&format!("[0x{:02X}] = true", cmd)));
+        }
+    }
+
+    // a layout that disagrees with its parser would have wireshark showing the wrong thing
+    #[test]
+    fn subcommand_layouts_match_parsers() {
+        for (subcmd, version, name, layout) in GameCommandAction::layouts() {
+            let size = layout.iter().map(|f| f.kind.size().unwrap()).sum::<usize>();
+            let mut data = vec![subcmd, (size / 4 + 1) as u8, 0, 0];
+            data.extend(vec![0u8; size]);
+            let cmd = GameCommand::parse_for(version.unwrap_or(ClientVersion::Gamecube), 0x60, 0, &data).unwrap();
+            assert!(!matches!(cmd.cmd, GameCommandAction::RawGameCommand(_)), "{} didn't parse", name);
+            assert_eq!(cmd.as_bytes(), data, "{}", name);
+        }
+    }
+}
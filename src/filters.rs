@@ -2,15 +2,15 @@ use std::convert::TryFrom;
 
 use mio::*;
 use mio::net::{TcpStream, TcpListener};
-use std::net::{SocketAddr, Ipv4Addr};
+use std::net::SocketAddr;
 
-use crate::proxy::{Proxy, SERVER, LISTENER};
+use crate::proxy::{Proxy, ProxyError, Location, SERVER, LISTENER, stream_interest};
+use crate::connection::Connection;
 use crate::packet::Packet;
 use crate::items::Item;
-use crate::gamecommand::{GameCommand, GameCommandAction};
-use crate::commands::{Command, CommandRunner};
-
-const LOCAL_PROXY_IP: [u8; 4] = [10, 0, 0, 179];
+use crate::gamecommand::GameCommandAction;
+use crate::commands::CommandRunner;
+use crate::capture::now;
 
 #[derive(Debug, Clone)]
 pub enum TargettedPacket {
@@ -18,35 +18,115 @@ pub enum TargettedPacket {
     Server(Packet),
 }
 
-pub type Filter = Fn(TargettedPacket, &mut Proxy) -> Vec<TargettedPacket>;
+pub type Filter = Fn(TargettedPacket, &mut Proxy) -> Result<Vec<TargettedPacket>, ProxyError>;
+
+// what every session runs packets through, in order
+pub fn default_filters() -> Vec<Box<Filter>> {
+    let mut filters: Vec<Box<Filter>> = Vec::new();
+    filters.push(Box::new(connection_redirect));
+    filters.push(Box::new(track_members));
+    filters.push(Box::new(track_items));
+    filters.push(Box::new(save_position));
+    // before chat_command so replies to commands aren't logged as chat
+    filters.push(Box::new(chat_log));
+    filters.push(Box::new(chat_command));
+    filters.push(Box::new(update_inventory));
+    filters
+}
+
+pub fn connection_redirect(mut pkt: TargettedPacket, proxy: &mut Proxy) -> Result<Vec<TargettedPacket>, ProxyError> {
+    // replaying a capture, there is nothing to reconnect
+    if proxy.server.sock().is_none() {
+        return Ok(vec![pkt]);
+    }
 
-pub fn connection_redirect(mut pkt: TargettedPacket, proxy: &mut Proxy) -> Vec<TargettedPacket> {
     if let TargettedPacket::Client(ref mut pkt) = pkt {
         if let Packet::Redirect(ref mut redirect) = pkt {
-            println!("redirecting! {:?}:{}", redirect.ip, redirect.port);
-            let new_sock = TcpStream::connect(SocketAddr::from((redirect.ip, redirect.port))).unwrap();
+            info!("[{}] redirecting! {:?}:{}", proxy.session, redirect.ip, redirect.port);
+            let new_sock = TcpStream::connect(SocketAddr::from((redirect.ip, redirect.port)))?;
             //poll.registry().deregister(&self.server).unwrap();
-            proxy.server = new_sock;
-            proxy.poll.registry().register(&mut proxy.server, SERVER, Interest::READABLE).unwrap();
+            proxy.server = Connection::new(new_sock);
+            proxy.server.register(proxy.poll.registry(), SERVER, stream_interest())?;
 
             proxy.server2proxy = None;
             proxy.proxy2server = None;
 
-            redirect.ip = LOCAL_PROXY_IP;
-            let mut ls = TcpListener::bind(SocketAddr::from((Ipv4Addr::new(0,0,0,0), 0))).unwrap();
-            redirect.port = ls.local_addr().unwrap().port();
-            println!("re-redirecting! {:?}:{}", redirect.ip, redirect.port);
-            proxy.poll.registry().register(&mut ls, LISTENER, Interest::READABLE).unwrap();
-            proxy.poll.registry().deregister(&mut proxy.server).unwrap();
-            proxy.poll.registry().deregister(&mut proxy.gamecube).unwrap();
-            println!("listening on: {:?}", ls);
+            redirect.ip = proxy.redirect_ip().ok_or(ProxyError::NoRedirectIp)?.octets();
+            let mut ls = TcpListener::bind(SocketAddr::new(proxy.config.listen.ip(), 0))?;
+            redirect.port = ls.local_addr()?.port();
+            info!("[{}] re-redirecting! {:?}:{}", proxy.session, redirect.ip, redirect.port);
+            proxy.poll.registry().register(&mut ls, LISTENER, Interest::READABLE)?;
+            proxy.server.deregister(proxy.poll.registry())?;
+            proxy.gamecube.deregister(proxy.poll.registry())?;
+            debug!("[{}] listening on: {:?}", proxy.session, ls);
             proxy.listener = Some(ls);
         }
     }
-    vec![pkt]
+    Ok(vec![pkt])
+}
+
+// joining somewhere starts the roster over, arrivals and leaves keep it current and
+// every game command either way says where its sender is
+pub fn track_members(pkt: TargettedPacket, proxy: &mut Proxy) -> Result<Vec<TargettedPacket>, ProxyError> {
+    let gamestate = &mut proxy.gamestate;
+    match pkt {
+        TargettedPacket::Client(Packet::GameCommand(ref cmd)) | TargettedPacket::Server(Packet::GameCommand(ref cmd)) => {
+            gamestate.roster.update(cmd);
+            if let Some(target) = cmd.target() {
+                trace!("[{}] game command from {} for {}", proxy.session, cmd.client, target);
+            }
+        },
+        TargettedPacket::Client(ref cpkt) => {
+            match cpkt {
+                Packet::LobbyJoin(join) => {
+                    gamestate.location = Location::Lobby { lobby: join.lobby, block: join.block };
+                    gamestate.self_client = join.client_id;
+                    gamestate.roster.join(&join.players);
+                },
+                Packet::GameJoin(join) => {
+                    gamestate.location = Location::Game;
+                    gamestate.self_client = join.client_id;
+                    gamestate.roster.join(&join.players);
+                },
+                Packet::LobbyArrive(join) | Packet::GameArrive(join) => {
+                    gamestate.roster.arrive(&join.players);
+                },
+                Packet::LobbyLeave(leave) | Packet::GameLeave(leave) => {
+                    if let Some(player) = gamestate.roster.get(leave.client_id) {
+                        info!("[{}] {} left", proxy.session, player);
+                    }
+                    gamestate.roster.leave(leave.client_id);
+                },
+                _ => return Ok(vec![pkt]),
+            }
+            debug!("[{}] {} with {}", proxy.session, gamestate.location,
+                   gamestate.roster.players().map(|p| p.to_string()).collect::<Vec<_>>().join(", "));
+        },
+        _ => {},
+    }
+    Ok(vec![pkt])
+}
+
+// our own drops and pickups go out to the server, everyone else's come in from it
+pub fn track_items(pkt: TargettedPacket, proxy: &mut Proxy) -> Result<Vec<TargettedPacket>, ProxyError> {
+    let items = &mut proxy.gamestate.items;
+    match pkt {
+        TargettedPacket::Client(Packet::GameCommand(ref cmd)) | TargettedPacket::Server(Packet::GameCommand(ref cmd)) => {
+            if let Some(item_id) = items.update(cmd) {
+                match items.get(item_id) {
+                    Some(item) => debug!("[{}] item {}", proxy.session, item),
+                    None => debug!("[{}] item {:08X} destroyed", proxy.session, item_id),
+                }
+                trace!("[{}] {} items, {} on the floor", proxy.session, items.len(), items.on_floor().count());
+            }
+        },
+        TargettedPacket::Client(Packet::LobbyJoin(_)) | TargettedPacket::Client(Packet::GameJoin(_)) => items.clear(),
+        _ => {},
+    }
+    Ok(vec![pkt])
 }
 
-pub fn save_position(pkt: TargettedPacket, proxy: &mut Proxy) -> Vec<TargettedPacket> {
+pub fn save_position(pkt: TargettedPacket, proxy: &mut Proxy) -> Result<Vec<TargettedPacket>, ProxyError> {
     if let TargettedPacket::Server(ref spkt) = pkt {
         if let Packet::GameCommand(cmd) = spkt {
             if let GameCommandAction::PlayerStop(ref action) = cmd.cmd {
@@ -68,27 +148,37 @@ pub fn save_position(pkt: TargettedPacket, proxy: &mut Proxy) -> Vec<TargettedPa
         }
     }
 
-    vec![pkt]
+    Ok(vec![pkt])
 }
 
 
-pub fn chat_command(pkt: TargettedPacket, proxy: &mut Proxy) -> Vec<TargettedPacket> {
+pub fn chat_command(pkt: TargettedPacket, proxy: &mut Proxy) -> Result<Vec<TargettedPacket>, ProxyError> {
     if let TargettedPacket::Server(ref spkt) = pkt {
         if let Packet::ChatMessage(chatmsg) = spkt {
-            if chatmsg.message.starts_with("/") {
-                println!("chat msg! {:?}", chatmsg.message);
-                let command = Command::parse(chatmsg.message.to_ascii_lowercase().chars().skip(1).collect());
+            let message = chatmsg.message();
+            if message.starts_with("/") {
+                info!("[{}] chat command: {:?}", proxy.session, message);
                 let mut commandrunner = CommandRunner::new();
-                return command.map(|command| {
-                    commandrunner.run(command, proxy)
-                }).unwrap_or(Vec::new())
+                return Ok(commandrunner.run_line(message.chars().skip(1).collect(), proxy))
+            }
+        }
+    }
+    Ok(vec![pkt])
+}
+
+// only what the server sends out, our own lines come back from it like everyone else's
+pub fn chat_log(pkt: TargettedPacket, proxy: &mut Proxy) -> Result<Vec<TargettedPacket>, ProxyError> {
+    if let TargettedPacket::Client(Packet::ChatMessage(ref chat)) = pkt {
+        if let Some(ref mut chatlog) = proxy.chatlog {
+            if let Err(err) = chatlog.write(now() / 1_000_000, proxy.session, &proxy.gamestate.location, chat) {
+                warn!("[{}] could not write chat log: {:?}", proxy.session, err);
             }
         }
     }
-    vec![pkt]
+    Ok(vec![pkt])
 }
 
-pub fn update_inventory(pkt: TargettedPacket, proxy: &mut Proxy) -> Vec<TargettedPacket> {
+pub fn update_inventory(pkt: TargettedPacket, proxy: &mut Proxy) -> Result<Vec<TargettedPacket>, ProxyError> {
     if let TargettedPacket::Server(ref spkt) = pkt {
         if let Packet::PlayerInventory(inventory_data) = spkt {
             proxy.gamestate.inventory = inventory_data.data.iter()
@@ -102,8 +192,87 @@ pub fn update_inventory(pkt: TargettedPacket, proxy: &mut Proxy) -> Vec<Targette
                     item
                 })
                 .collect();
-            dbg!(&proxy.gamestate.inventory);
+            debug!("[{}] inventory: {} items", proxy.session, proxy.gamestate.inventory.len());
+            trace!("[{}] {:?}", proxy.session, proxy.gamestate.inventory);
         }
     }
-    vec![pkt]
+    Ok(vec![pkt])
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::lobby::tests::{join_lobby, lobby_entry};
+    use crate::packet::ClientVersion;
+    use crate::gameitems::ItemPlace;
+
+    fn from_server(proxy: &mut Proxy, cmd: u16, flag: u32, data: Vec<u8>) {
+        let pkt = Packet::parse(ClientVersion::Gamecube, cmd, flag, &data);
+        proxy.filter_packet(&default_filters(), TargettedPacket::Client(pkt)).unwrap();
+    }
+
+    fn from_gamecube(proxy: &mut Proxy, cmd: u16, flag: u32, data: Vec<u8>) {
+        let pkt = Packet::parse(ClientVersion::Gamecube, cmd, flag, &data);
+        proxy.filter_packet(&default_filters(), TargettedPacket::Server(pkt)).unwrap();
+    }
+
+    #[test]
+    fn members_follow_joins_and_leaves() {
+        let mut proxy = Proxy::offline(0, Config::for_tests(&[])).unwrap();
+
+        from_server(&mut proxy, 0x67, 2, join_lobby(1, &[
+            lobby_entry(0, 42000001, "Kireek", 2, 6, 200),
+            lobby_entry(1, 42000002, "Asphodel", 8, 9, 57),
+        ]));
+        assert_eq!(proxy.gamestate.location, Location::Lobby { lobby: 2, block: 1 });
+        assert_eq!(proxy.gamestate.self_client, 1);
+        assert_eq!(proxy.gamestate.roster.players().map(|p| p.client_id).collect::<Vec<_>>(), vec![0, 1]);
+
+        from_server(&mut proxy, 0x68, 1, join_lobby(1, &[lobby_entry(5, 42000005, "Ash", 4, 1, 10)]));
+        from_server(&mut proxy, 0x69, 0, vec![0, 0, 1, 0]);
+        assert_eq!(proxy.gamestate.roster.players().map(|p| p.header.as_ref().unwrap().name.as_str()).collect::<Vec<_>>(), vec!["Asphodel", "Ash"]);
+        assert_eq!(proxy.gamestate.roster.get(5).unwrap().header.as_ref().unwrap().level, Some(10));
+    }
+    #[test]
+    fn items_follow_drops_and_pickups() {
+        let mut proxy = Proxy::offline(0, Config::for_tests(&[])).unwrap();
+
+        // a monomate out of a box on floor 2
+        from_server(&mut proxy, 0x60, 0, vec![
+            0x5F, 0x0B, 0x00, 0x00, 0x02, 0x02, 0x10, 0x00,
+            0x00, 0x00, 0x80, 0x3F, 0x00, 0x00, 0x00, 0x40,
+            0x00, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00,
+            0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x05, 0x00, 0x81, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00,
+        ]);
+        let item = proxy.gamestate.items.get(0x00810005).unwrap();
+        assert_eq!(item.place, ItemPlace::Floor { floor: 2, x: 1.0, z: 2.0 });
+        assert_eq!(item.rows, Some([0x03000000, 0x00010000, 0, 0]));
+
+        from_server(&mut proxy, 0x60, 0, vec![0x59, 0x03, 0x00, 0x00, 0x01, 0x00, 0x02, 0x00, 0x05, 0x00, 0x81, 0x00]);
+        assert_eq!(proxy.gamestate.items.get(0x00810005).unwrap().place, ItemPlace::Held(1));
+        assert_eq!(proxy.gamestate.items.on_floor().count(), 0);
+
+        // we drop it somewhere else, it's still a monomate
+        from_gamecube(&mut proxy, 0x60, 0, vec![
+            0x2A, 0x06, 0x01, 0x00, 0x01, 0x00, 0x03, 0x00,
+            0x05, 0x00, 0x81, 0x00, 0x00, 0x00, 0x80, 0x40,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xA0, 0x40,
+        ]);
+        let item = proxy.gamestate.items.get(0x00810005).unwrap();
+        assert_eq!(item.place, ItemPlace::Floor { floor: 3, x: 4.0, z: 5.0 });
+        assert_eq!(item.rows, Some([0x03000000, 0x00010000, 0, 0]));
+
+        from_gamecube(&mut proxy, 0x60, 0, vec![0x29, 0x03, 0x01, 0x00, 0x05, 0x00, 0x81, 0x00, 0x01, 0x00, 0x00, 0x00]);
+        assert!(proxy.gamestate.items.get(0x00810005).is_none());
+
+        // item ids start over in the next game
+        from_server(&mut proxy, 0x60, 0, vec![0x59, 0x03, 0x00, 0x00, 0x01, 0x00, 0x02, 0x00, 0x06, 0x00, 0x81, 0x00]);
+        assert_eq!(proxy.gamestate.items.len(), 1);
+        from_server(&mut proxy, 0x67, 1, join_lobby(1, &[lobby_entry(1, 42000002, "Asphodel", 8, 9, 57)]));
+        assert_eq!(proxy.gamestate.items.len(), 0);
+    }
 }
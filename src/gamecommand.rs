@@ -2,10 +2,10 @@ use std::io::Cursor;
 use std::io::{Read, Write};
 use byteorder::{ReadBytesExt, WriteBytesExt, LittleEndian, BigEndian};
 
-use crate::packet::PacketData;
+use crate::packet::{PacketData, ParseError, ClientVersion, Field, FieldKind, field};
 
 trait GameCommandData {
-    fn parse(cmd: u8, data: &Vec<u8>) -> Self where Self: Sized;
+    fn parse(cmd: u8, data: &Vec<u8>) -> Result<Self, ParseError> where Self: Sized;
     fn as_bytes(&self) -> Vec<u8>;
 }
 
@@ -28,6 +28,16 @@ impl GameCommandBytes {
         self
     }
 
+    fn _u8(mut self, item: u8) -> GameCommandBytes {
+        self.buffer.write_u8(item).unwrap();
+        self
+    }
+
+    fn _u16(mut self, item: u16) -> GameCommandBytes {
+        self.buffer.write_u16::<LittleEndian>(item).unwrap();
+        self
+    }
+
     fn _u32(mut self, item: u32) -> GameCommandBytes {
         self.buffer.write_u32::<LittleEndian>(item).unwrap();
         self
@@ -67,118 +77,208 @@ impl GameCommandParser {
         }
     }
 
-    fn _u32(&mut self) -> u32 {
-        self.cursor.read_u32::<LittleEndian>().unwrap()
+    fn _u8(&mut self) -> Result<u8, ParseError> {
+        Ok(self.cursor.read_u8()?)
     }
 
-    fn _f32(&mut self) -> f32 {
-        self.cursor.read_f32::<LittleEndian>().unwrap()
+    fn _u16(&mut self) -> Result<u16, ParseError> {
+        Ok(self.cursor.read_u16::<LittleEndian>()?)
     }
-}
-
 
-#[derive(Debug, Clone)]
-pub struct PlayerArea {
-    pub floor: u32,
-}
-
-impl GameCommandData for PlayerArea {
-    fn parse(_gcmd: u8, data: &Vec<u8>) -> PlayerArea {
-        let mut parser = GameCommandParser::new(data.clone());
-        PlayerArea {
-            floor: parser._u32(),
-        }
+    fn _u32(&mut self) -> Result<u32, ParseError> {
+        Ok(self.cursor.read_u32::<LittleEndian>()?)
     }
 
-    fn as_bytes(&self) -> Vec<u8> {
-        GameCommandBytes::new()
-            .cmd(0x1F)
-            ._u32(self.floor)
-            .build()
+    fn _u32_be(&mut self) -> Result<u32, ParseError> {
+        Ok(self.cursor.read_u32::<BigEndian>()?)
     }
-}
 
-#[derive(Debug, Clone)]
-pub struct PlayerWalk {
-    pub x: f32,
-    pub z: f32,
-    unknown: f32,
+    fn _f32(&mut self) -> Result<f32, ParseError> {
+        Ok(self.cursor.read_f32::<LittleEndian>()?)
+    }
 }
 
-impl GameCommandData for PlayerWalk {
-    fn parse(_gcmd: u8, data: &Vec<u8>) -> PlayerWalk {
-        let mut parser = GameCommandParser::new(data.clone());
-        PlayerWalk {
-            x: parser._f32(),
-            z: parser._f32(),
-            unknown: parser._f32(),
-        }
-    }
 
-    fn as_bytes(&self) -> Vec<u8> {
-        GameCommandBytes::new()
-            .cmd(0x40)
-            ._f32(self.x)
-            ._f32(self.z)
-            ._f32(self.unknown)
-            .build()
-    }
+// the rust type, read and write of each FieldKind a subcommand can have
+macro_rules! subcommand_field {
+    (type U8) => { u8 };
+    (type U16) => { u16 };
+    (type U32) => { u32 };
+    (type U32BE) => { u32 };
+    (type F32) => { f32 };
+    (read $parser:ident U8) => { $parser._u8()? };
+    (read $parser:ident U16) => { $parser._u16()? };
+    (read $parser:ident U32) => { $parser._u32()? };
+    (read $parser:ident U32BE) => { $parser._u32_be()? };
+    (read $parser:ident F32) => { $parser._f32()? };
+    (write $bytes:ident U8 $value:expr) => { $bytes._u8($value) };
+    (write $bytes:ident U16 $value:expr) => { $bytes._u16($value) };
+    (write $bytes:ident U32 $value:expr) => { $bytes._u32($value) };
+    (write $bytes:ident U32BE $value:expr) => { $bytes._u32_be($value) };
+    (write $bytes:ident F32 $value:expr) => { $bytes._f32($value) };
 }
 
-#[derive(Debug, Clone)]
-pub struct PlayerRun {
-    pub x: f32,
-    pub z: f32,
+// `if BlueBurst` after a subcommand number, for the ones only one version has
+macro_rules! subcommand_version {
+    () => { None };
+    ($version:ident) => { Some(ClientVersion::$version) };
 }
 
-impl GameCommandData for PlayerRun {
-    fn parse(_gcmd: u8, data: &Vec<u8>) -> PlayerRun {
-        let mut parser = GameCommandParser::new(data.clone());
-        PlayerRun {
-            x: parser._f32(),
-            z: parser._f32(),
+// every subcommand with known fields is declared once in the subcommands! list below.
+// each gets a struct with its LAYOUT, parse and as_bytes, plus a GameCommandAction
+// variant, a parse arm and a layouts() entry. fields are read and written in order
+macro_rules! subcommands {
+    ($($gcmd:literal $(if $version:ident)? => $name:ident { $($vis:vis $field:ident: $kind:ident),* $(,)? })*) => {
+        $(
+            #[derive(Debug, Clone)]
+            pub struct $name {
+                $($vis $field: subcommand_field!(type $kind),)*
+            }
+
+            impl $name {
+                pub const LAYOUT: &'static [Field] = &[
+                    $(field(stringify!($field), FieldKind::$kind),)*
+                ];
+            }
+
+            impl GameCommandData for $name {
+                fn parse(_gcmd: u8, data: &Vec<u8>) -> Result<$name, ParseError> {
+                    let mut parser = GameCommandParser::new(data.clone());
+                    Ok($name {
+                        $($field: subcommand_field!(read parser $kind),)*
+                    })
+                }
+
+                fn as_bytes(&self) -> Vec<u8> {
+                    let bytes = GameCommandBytes::new().cmd($gcmd);
+                    $(let bytes = subcommand_field!(write bytes $kind self.$field);)*
+                    bytes.build()
+                }
+            }
+        )*
+
+        #[derive(Debug, Clone)]
+        pub enum GameCommandAction {
+            $($name($name),)*
+            RawGameCommand(RawGameCommand),
         }
-    }
 
-    fn as_bytes(&self) -> Vec<u8> {
-        GameCommandBytes::new()
-            .cmd(0x42)
-            ._f32(self.x)
-            ._f32(self.z)
-            .build()
-    }
-}
+        impl GameCommandAction {
+            // without a version only the subcommands every version shares are known
+            fn parse(version: Option<ClientVersion>, gcmd: u8, data: &Vec<u8>) -> Result<GameCommandAction, ParseError> {
+                match gcmd {
+                    $($gcmd if subcommand_version!($($version)?).map_or(true, |only| version == Some(only))
+                        => $name::parse(gcmd, data).map(GameCommandAction::$name),)*
+                    _ => RawGameCommand::parse(gcmd, data).map(GameCommandAction::RawGameCommand),
+                }
+            }
 
-#[derive(Debug, Clone)]
-pub struct PlayerStop {
-    unknown1: u32,
-    unknown2: u32,
-    pub x: f32,
-    pub y: f32,
-    pub z: f32,
-}
+            fn as_bytes(&self) -> Vec<u8> {
+                match self {
+                    $(GameCommandAction::$name(cmd) => cmd.as_bytes(),)*
+                    GameCommandAction::RawGameCommand(cmd) => cmd.as_bytes(),
+                }
+            }
 
-impl GameCommandData for PlayerStop {
-    fn parse(_gcmd: u8, data: &Vec<u8>) -> PlayerStop {
-        let mut parser = GameCommandParser::new(data.clone());
-        PlayerStop {
-            unknown1: parser._u32(),
-            unknown2: parser._u32(),
-            x: parser._f32(),
-            y: parser._f32(),
-            z: parser._f32(),
+            // (subcommand, the one version it's for if it isn't for all of them, name, layout)
+            // of every subcommand we know the fields of
+            pub fn layouts() -> Vec<(u8, Option<ClientVersion>, &'static str, &'static [Field])> {
+                vec![
+                    $(($gcmd, subcommand_version!($($version)?), stringify!($name), $name::LAYOUT),)*
+                ]
+            }
         }
     }
+}
 
-    fn as_bytes(&self) -> Vec<u8> {
-        GameCommandBytes::new()
-            .cmd(0x3E)
-            ._u32(self.unknown1)
-            ._u32(self.unknown2)
-            ._f32(self.x)
-            ._f32(self.y)
-            ._f32(self.z)
-            .build()
+// item_row1-4 and item_id together are the 20 bytes of item data the game uses everywhere
+subcommands! {
+    0x1F => PlayerArea {
+        pub floor: U32,
+    }
+    0x29 => DestroyItem {
+        pub item_id: U32,
+        pub amount: U32,
+    }
+    0x2A => PlayerDropItem {
+        unknown: U16,
+        pub floor: U16,
+        pub item_id: U32,
+        pub x: F32,
+        pub y: F32,
+        pub z: F32,
+    }
+    // straight into someone's inventory, like from a shop or a trade
+    0x2B => CreateItem {
+        pub item_row1: U32BE,
+        pub item_row2: U32BE,
+        pub item_row3: U32BE,
+        pub item_id: U32,
+        pub item_row4: U32BE,
+        unknown: U32,
+    }
+    0x3E => PlayerStop {
+        unknown1: U32,
+        unknown2: U32,
+        pub x: F32,
+        pub y: F32,
+        pub z: F32,
+    }
+    0x40 => PlayerWalk {
+        pub x: F32,
+        pub z: F32,
+        unknown: F32,
+    }
+    0x42 => PlayerRun {
+        pub x: F32,
+        pub z: F32,
+    }
+    // the leader telling everyone who got it
+    0x59 => PickUpItem {
+        pub client_id: U16,
+        pub floor: U16,
+        pub item_id: U32,
+    }
+    0x5A => PickUpRequest {
+        pub item_id: U32,
+        pub floor: U16,
+        unused: U16,
+    }
+    0x5D => ItemDrop {
+        pub floor: U32,
+        pub x: F32,
+        pub z: F32,
+        pub item_row1: U32BE,
+        pub item_row2: U32BE,
+        pub item_row3: U32BE,
+        pub itemdrop_id: U32,
+        pub item_row4: U32BE,
+        pub unknown: U32,
+    }
+    // from an enemy or a box
+    0x5F => FloorItemDrop {
+        pub floor: U8,
+        // 1 for an enemy, 2 for a box
+        pub source: U8,
+        pub entity_id: U16,
+        pub x: F32,
+        pub z: F32,
+        unknown: U32,
+        pub item_row1: U32BE,
+        pub item_row2: U32BE,
+        pub item_row3: U32BE,
+        pub item_id: U32,
+        pub item_row4: U32BE,
+        unused: U32,
+    }
+    // blue burst's 0x2B, other versions use 0xBE for something else
+    0xBE if BlueBurst => CreateItemBB {
+        pub item_row1: U32BE,
+        pub item_row2: U32BE,
+        pub item_row3: U32BE,
+        pub item_id: U32,
+        pub item_row4: U32BE,
+        unknown: U32,
     }
 }
 
@@ -189,11 +289,11 @@ pub struct RawGameCommand {
 }
 
 impl GameCommandData for RawGameCommand {
-    fn parse(gcmd: u8, data: &Vec<u8>) -> RawGameCommand {
-        RawGameCommand {
+    fn parse(gcmd: u8, data: &Vec<u8>) -> Result<RawGameCommand, ParseError> {
+        Ok(RawGameCommand {
             gcmd: gcmd,
             data: data.clone(),
-        }
+        })
     }
 
     fn as_bytes(&self) -> Vec<u8> {
@@ -208,121 +308,259 @@ impl GameCommandData for RawGameCommand {
     }
 }
 
+// 0x60 goes to everyone, 0x62 to the client in flag. 0x6C and 0x6D are the same for
+// subcommands too big for the one byte size and put a 4 byte size after the usual header
 #[derive(Debug, Clone)]
-pub struct ItemDrop {
-    pub floor: u32,
-    pub x: f32,
-    pub z: f32,
-    pub y: f32,
-    pub item_row1: u32,
-    pub item_row2: u32,
-    pub item_row3: u32,
-    pub itemdrop_id: u32,
-    pub item_row4: u32,
-    pub unknown: u32,
+pub struct GameCommand {
+    pub packet_cmd: u16,
+    pub flag: u32,
+    // in 4 byte units, kept as sent since it doesn't always match the body
+    pub size: u8,
+    pub client: u8,
+    pub unknown: u8,
+    // in bytes for 0x6C/0x6D, also kept as sent
+    pub extended_size: Option<u32>,
+    pub cmd: GameCommandAction,
 }
 
-impl /*GameCommandData for*/ ItemDrop {
-    /*fn parse(gcmd: u8, data: &Vec<u8>) -> ItemDrop {
-        ItemDrop {
+impl GameCommand {
+    // the subcommand's own fields follow these
+    pub const LAYOUT: &'static [Field] = &[
+        field("subcommand", FieldKind::U8),
+        field("size", FieldKind::U8),
+        field("client", FieldKind::U8),
+        field("unknown", FieldKind::U8),
+    ];
+
+    pub const EXTENDED_LAYOUT: &'static [Field] = &[
+        field("subcommand", FieldKind::U8),
+        field("size", FieldKind::U8),
+        field("client", FieldKind::U8),
+        field("unknown", FieldKind::U8),
+        field("extended_size", FieldKind::U32),
+    ];
+
+    pub fn new(client: u8, cmd: GameCommandAction) -> GameCommand {
+        GameCommand {
+            packet_cmd: 0x60,
+            flag: 0,
+            size: cmd.as_bytes()[1],
+            client: client,
+            unknown: 0,
+            extended_size: None,
+            cmd: cmd,
         }
-}*/
-
-    //fn make_wep()
-    //fn make_armor()
-    //fn make_shield()
-    //fn make_tech()
-    //fn make_tool()
-    //fn make_mag()
-    //fn make_meseta()
-
-    fn as_bytes(&self) -> Vec<u8> {
-        GameCommandBytes::new()
-            .cmd(0x5D)
-            ._u32(self.floor)
-            ._f32(self.x)
-            ._f32(self.z)
-            //._f32(self.y)
-            ._u32_be(self.item_row1)
-            ._u32_be(self.item_row2)
-            ._u32_be(self.item_row3)
-            ._u32(self.itemdrop_id)
-            ._u32_be(self.item_row4)
-            ._u32(self.unknown)
-            .build()
-        // TODO: self.size is based on 32 bit items, `assert len(self.data) % 4 == 0`?
-        /*let mut buf: Vec<u8> = Vec::new();
-        buf.write_u8(0x5D).unwrap();
-        buf.write_u8(0x0A).unwrap();
-        buf.write_u8(0).unwrap();
-        buf.write_u8(0).unwrap();
-        buf*/
     }
-}
 
+    fn extended(packet_cmd: u16) -> bool {
+        packet_cmd == 0x6C || packet_cmd == 0x6D
+    }
 
-#[derive(Debug, Clone)]
-pub enum GameCommandAction {
-    PlayerWalk(PlayerWalk),
-    PlayerRun(PlayerRun),
-    PlayerStop(PlayerStop),
-    PlayerArea(PlayerArea),
-    ItemDrop(ItemDrop),
-
-    RawGameCommand(RawGameCommand),
-}
+    // the one client a targeted command is meant for
+    pub fn target(&self) -> Option<u8> {
+        match self.packet_cmd {
+            0x62 | 0x6D => Some(self.flag as u8),
+            _ => None,
+        }
+    }
 
-#[derive(Debug, Clone)]
-pub struct GameCommand {
-    pub flag: u8,
-    pub client: u8,
-    pub unknown: u8,
-    pub cmd: GameCommandAction,
-}
+    // some subcommands only mean what they're declared as on one version, PacketData::parse
+    // doesn't know the version so it leaves those raw
+    pub fn parse_for(version: ClientVersion, packet_cmd: u16, flag: u32, data: &Vec<u8>) -> Result<GameCommand, ParseError> {
+        GameCommand::read(Some(version), packet_cmd, flag, data)
+    }
 
-impl PacketData for GameCommand {
-    fn parse(_cmd: u8, flag: u8, data: &Vec<u8>) -> GameCommand {
+    fn read(version: Option<ClientVersion>, packet_cmd: u16, flag: u32, data: &Vec<u8>) -> Result<GameCommand, ParseError> {
         let mut cur = Cursor::new(data);
-        let gcmd = cur.read_u8().unwrap();
-        let _size = cur.read_u8().unwrap();
-        let client = cur.read_u8().unwrap();
-        let unknown = cur.read_u8().unwrap();
-
+        let gcmd = cur.read_u8()?;
+        let size = cur.read_u8()?;
+        let client = cur.read_u8()?;
+        let unknown = cur.read_u8()?;
+        let extended_size = match GameCommand::extended(packet_cmd) {
+            true => Some(cur.read_u32::<LittleEndian>()?),
+            false => None,
+        };
         let mut cmd_data = Vec::new();
-        cur.read_to_end(&mut cmd_data).unwrap();
+        cur.read_to_end(&mut cmd_data)?;
 
-        GameCommand {
+        let cmd = GameCommandAction::parse(version, gcmd, &cmd_data);
+
+        // anything past the fields we know about would get lost on the way back out
+        let cmd = cmd.and_then(|cmd| {
+            match cmd.as_bytes().len() - 4 {
+                len if len < cmd_data.len() => Err(ParseError::TrailingData(cmd_data.len() - len)),
+                _ => Ok(cmd),
+            }
+        });
+
+        // a subcommand we can't make sense of still goes through untouched
+        let cmd = cmd.unwrap_or_else(|err| {
+            warn!("could not parse game command {:02X}: {}", gcmd, err);
+            GameCommandAction::RawGameCommand(RawGameCommand {
+                gcmd: gcmd,
+                data: cmd_data.clone(),
+            })
+        });
+
+        Ok(GameCommand {
+            packet_cmd: packet_cmd,
             flag: flag,
+            size: size,
             client: client,
             unknown: unknown,
-            cmd: match gcmd {
-                0x1F => GameCommandAction::PlayerArea(PlayerArea::parse(gcmd, &cmd_data)),
-                0x3E => GameCommandAction::PlayerStop(PlayerStop::parse(gcmd, &cmd_data)),
-                0x40 => GameCommandAction::PlayerWalk(PlayerWalk::parse(gcmd, &cmd_data)),
-                0x42 => GameCommandAction::PlayerRun(PlayerRun::parse(gcmd, &cmd_data)),
-                _ => GameCommandAction::RawGameCommand(RawGameCommand::parse(gcmd, &cmd_data)),
-            }
-        }
+            extended_size: extended_size,
+            cmd: cmd,
+        })
+    }
+}
+
+impl PacketData for GameCommand {
+    fn parse(packet_cmd: u16, flag: u32, data: &Vec<u8>) -> Result<GameCommand, ParseError> {
+        GameCommand::read(None, packet_cmd, flag, data)
+    }
+
+    fn cmd(&self) -> u16 {
+        self.packet_cmd
+    }
+
+    fn flag(&self) -> u32 {
+        self.flag
     }
 
     fn as_bytes(&self) -> Vec<u8> {
-        let mut data = match &self.cmd {
-            GameCommandAction::PlayerArea(cmd) => cmd.as_bytes(),
-            GameCommandAction::PlayerStop(cmd) => cmd.as_bytes(),
-            GameCommandAction::PlayerWalk(cmd) => cmd.as_bytes(),
-            GameCommandAction::PlayerRun(cmd) => cmd.as_bytes(),
-            GameCommandAction::ItemDrop(cmd) => cmd.as_bytes(),
-            GameCommandAction::RawGameCommand(cmd) => cmd.as_bytes(),
-        };
+        let mut data = self.cmd.as_bytes();
 
+        data[0x01] = self.size;
         data[0x02] = self.client;
         data[0x03] = self.unknown;
+        if let Some(extended_size) = self.extended_size {
+            let mut size = Vec::new();
+            size.write_u32::<LittleEndian>(extended_size).unwrap();
+            data.splice(4..4, size);
+        }
+        data
+    }
+}
 
-        let mut buf: Vec<u8> = Vec::new();
-        buf.write_u8(0x60).unwrap();
-        buf.write_u8(self.flag).unwrap();
-        buf.write_u16::<LittleEndian>(0x04 + data.len() as u16).unwrap();
-        buf.write(&data).unwrap();
-        buf
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncated_subcommands() {
+        // (subcommand, full body after the 4 byte subcommand header)
+        let subcommands = GameCommandAction::layouts()
+            .into_iter()
+            .map(|(gcmd, version, _, layout)| (gcmd, version, vec![0u8; layout.iter().map(|f| f.kind.size().unwrap()).sum()]))
+            .collect::<Vec<_>>();
+
+        for (gcmd, version, body) in subcommands {
+            for len in 0..=body.len() {
+                let mut data = vec![gcmd, (len / 4 + 1) as u8, 0x01, 0x00];
+                data.extend_from_slice(&body[..len]);
+                let cmd = GameCommand::parse_for(version.unwrap_or(ClientVersion::Gamecube), 0x60, 0, &data).unwrap();
+                assert_eq!(cmd.client, 0x01);
+                match cmd.cmd {
+                    GameCommandAction::RawGameCommand(ref raw) if len < body.len() => {
+                        assert_eq!(raw.gcmd, gcmd);
+                        assert_eq!(raw.data, &body[..len]);
+                    },
+                    GameCommandAction::RawGameCommand(_) => panic!("{:02X} full body parsed as raw", gcmd),
+                    _ if len < body.len() => panic!("{:02X} len {} parsed as {:?}", gcmd, len, cmd.cmd),
+                    _ => {},
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn targeted_and_large() {
+        // 0x62 is the normal header with the target in flag
+        let data = vec![0x1F, 0x02, 0x01, 0x00, 0x03, 0x00, 0x00, 0x00];
+        let cmd = GameCommand::parse(0x62, 2, &data).unwrap();
+        assert_eq!(cmd.target(), Some(2));
+        assert!(matches!(cmd.cmd, GameCommandAction::PlayerArea(PlayerArea { floor: 3 })));
+        assert_eq!(cmd.as_bytes(), data);
+
+        // 0x6D has the extended size between the header and the body
+        let data = vec![0x42, 0x00, 0x01, 0x00, 0x10, 0x00, 0x00, 0x00, 0, 0, 0x80, 0x3F, 0, 0, 0, 0x40];
+        let cmd = GameCommand::parse(0x6D, 3, &data).unwrap();
+        assert_eq!(cmd.target(), Some(3));
+        assert_eq!(cmd.extended_size, Some(0x10));
+        match cmd.cmd {
+            GameCommandAction::PlayerRun(ref run) => assert_eq!((run.x, run.z), (1.0, 2.0)),
+            ref action => panic!("parsed as {:?}", action),
+        }
+        assert_eq!(cmd.as_bytes(), data);
+        assert_eq!(cmd.cmd(), 0x6D);
+
+        assert!(matches!(GameCommand::parse(0x6C, 0, &vec![0x42, 0x00, 0x01, 0x00, 0x10]), Err(ParseError::Truncated)));
+        assert_eq!(GameCommand::parse(0x6C, 0, &data).unwrap().target(), None);
+    }
+
+    #[test]
+    fn item_subcommands() {
+        // a monomate from a box on floor 2
+        let data = vec![
+            0x5F, 0x0B, 0x00, 0x00, 0x02, 0x02, 0x10, 0x00,
+            0x00, 0x00, 0x80, 0x3F, 0x00, 0x00, 0x00, 0x40,
+            0x00, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00,
+            0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x05, 0x00, 0x81, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00,
+        ];
+        let cmd = GameCommand::parse(0x60, 0, &data).unwrap();
+        match cmd.cmd {
+            GameCommandAction::FloorItemDrop(ref drop) => {
+                assert_eq!((drop.floor, drop.source, drop.entity_id), (2, 2, 0x10));
+                assert_eq!((drop.x, drop.z), (1.0, 2.0));
+                assert_eq!(drop.item_row1, 0x03000000);
+                assert_eq!(drop.item_row2, 0x00010000);
+                assert_eq!(drop.item_id, 0x00810005);
+            },
+            ref action => panic!("parsed as {:?}", action),
+        }
+        assert_eq!(cmd.as_bytes(), data);
+
+        let data = vec![0x59, 0x03, 0x01, 0x00, 0x01, 0x00, 0x02, 0x00, 0x05, 0x00, 0x81, 0x00];
+        match GameCommand::parse(0x60, 0, &data).unwrap().cmd {
+            GameCommandAction::PickUpItem(pickup) => assert_eq!((pickup.client_id, pickup.floor, pickup.item_id), (1, 2, 0x00810005)),
+            action => panic!("parsed as {:?}", action),
+        }
+
+        // dropped at (1, 0, 2) on floor 2, y is there like in every other position
+        let data = vec![
+            0x2A, 0x06, 0x01, 0x00, 0x01, 0x00, 0x02, 0x00,
+            0x05, 0x00, 0x81, 0x00, 0x00, 0x00, 0x80, 0x3F,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x40,
+        ];
+        let cmd = GameCommand::parse(0x60, 0, &data).unwrap();
+        match cmd.cmd {
+            GameCommandAction::PlayerDropItem(ref drop) => {
+                assert_eq!((drop.floor, drop.item_id), (2, 0x00810005));
+                assert_eq!((drop.x, drop.y, drop.z), (1.0, 0.0, 2.0));
+            },
+            ref action => panic!("parsed as {:?}", action),
+        }
+        assert_eq!(cmd.as_bytes(), data);
+    }
+
+    #[test]
+    fn create_item_bb_only() {
+        let mut data = vec![0xBE, 0x07, 0x00, 0x00];
+        data.extend(vec![0x11; 24]);
+        let bb = GameCommand::parse_for(ClientVersion::BlueBurst, 0x60, 0, &data).unwrap();
+        assert!(matches!(bb.cmd, GameCommandAction::CreateItemBB(_)));
+        let gc = GameCommand::parse_for(ClientVersion::Gamecube, 0x60, 0, &data).unwrap();
+        assert!(matches!(gc.cmd, GameCommandAction::RawGameCommand(_)));
+        assert_eq!(gc.as_bytes(), data);
+
+        // any length is fine when it was never going to be parsed as CreateItemBB
+        let data = vec![0xBE, 0x02, 0x00, 0x00, 0x01, 0x02, 0x03, 0x04];
+        let gc = GameCommand::parse_for(ClientVersion::Gamecube, 0x60, 0, &data).unwrap();
+        assert!(matches!(gc.cmd, GameCommandAction::RawGameCommand(_)));
+        assert_eq!(gc.as_bytes(), data);
+        assert!(matches!(GameCommand::parse(0x60, 0, &data).unwrap().cmd, GameCommandAction::RawGameCommand(_)));
     }
 }
@@ -0,0 +1,124 @@
+// every item we've seen show up in the current game, by item id. drops put them on
+// the floor, pickups and creates put them in someone's inventory, destroys forget them
+use std::collections::BTreeMap;
+
+use crate::gamecommand::{GameCommand, GameCommandAction};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ItemPlace {
+    Floor { floor: u32, x: f32, z: f32 },
+    // in this client's inventory
+    Held(u8),
+}
+
+#[derive(Debug, Clone)]
+pub struct GameItem {
+    pub item_id: u32,
+    // item_row1-4, None for something a player dropped before we saw it any other way
+    pub rows: Option<[u32; 4]>,
+    pub place: ItemPlace,
+}
+
+impl GameItem {
+    fn new(item_id: u32, rows: Option<[u32; 4]>, place: ItemPlace) -> GameItem {
+        GameItem {
+            item_id: item_id,
+            rows: rows,
+            place: place,
+        }
+    }
+}
+
+// 00810005 03000000 00010000 00000000 00000000 on floor 2 at (1.0, 2.0)
+impl std::fmt::Display for GameItem {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:08X}", self.item_id)?;
+        if let Some(rows) = self.rows {
+            write!(f, " {:08X} {:08X} {:08X} {:08X}", rows[0], rows[1], rows[2], rows[3])?;
+        }
+        match self.place {
+            ItemPlace::Floor { floor, x, z } => write!(f, " on floor {} at ({:.1}, {:.1})", floor, x, z),
+            ItemPlace::Held(client_id) => write!(f, " held by {}", client_id),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct GameItems {
+    items: BTreeMap<u32, GameItem>,
+}
+
+impl GameItems {
+    pub fn new() -> GameItems {
+        GameItems {
+            items: BTreeMap::new(),
+        }
+    }
+
+    // item ids only mean something within one game
+    pub fn clear(&mut self) {
+        self.items.clear();
+    }
+
+    // the id of the item cmd was about, if it was about one
+    pub fn update(&mut self, cmd: &GameCommand) -> Option<u32> {
+        let item_id = match cmd.cmd {
+            GameCommandAction::FloorItemDrop(ref drop) => {
+                let rows = [drop.item_row1, drop.item_row2, drop.item_row3, drop.item_row4];
+                let place = ItemPlace::Floor { floor: drop.floor as u32, x: drop.x, z: drop.z };
+                self.items.insert(drop.item_id, GameItem::new(drop.item_id, Some(rows), place));
+                drop.item_id
+            },
+            GameCommandAction::ItemDrop(ref drop) => {
+                let rows = [drop.item_row1, drop.item_row2, drop.item_row3, drop.item_row4];
+                let place = ItemPlace::Floor { floor: drop.floor, x: drop.x, z: drop.z };
+                self.items.insert(drop.itemdrop_id, GameItem::new(drop.itemdrop_id, Some(rows), place));
+                drop.itemdrop_id
+            },
+            GameCommandAction::CreateItem(ref create) => {
+                let rows = [create.item_row1, create.item_row2, create.item_row3, create.item_row4];
+                self.items.insert(create.item_id, GameItem::new(create.item_id, Some(rows), ItemPlace::Held(cmd.client)));
+                create.item_id
+            },
+            GameCommandAction::CreateItemBB(ref create) => {
+                let rows = [create.item_row1, create.item_row2, create.item_row3, create.item_row4];
+                self.items.insert(create.item_id, GameItem::new(create.item_id, Some(rows), ItemPlace::Held(cmd.client)));
+                create.item_id
+            },
+            // a player drop only has the id, what it is comes from when we last saw it
+            GameCommandAction::PlayerDropItem(ref drop) => {
+                self.place(drop.item_id, ItemPlace::Floor { floor: drop.floor as u32, x: drop.x, z: drop.z });
+                drop.item_id
+            },
+            GameCommandAction::PickUpItem(ref pickup) => {
+                self.place(pickup.item_id, ItemPlace::Held(pickup.client_id as u8));
+                pickup.item_id
+            },
+            // stack sizes aren't tracked, so using one of a stack forgets the whole stack
+            GameCommandAction::DestroyItem(ref destroy) => {
+                self.items.remove(&destroy.item_id);
+                destroy.item_id
+            },
+            _ => return None,
+        };
+        Some(item_id)
+    }
+
+    fn place(&mut self, item_id: u32, place: ItemPlace) {
+        self.items.entry(item_id)
+            .or_insert_with(|| GameItem::new(item_id, None, place))
+            .place = place;
+    }
+
+    pub fn get(&self, item_id: u32) -> Option<&GameItem> {
+        self.items.get(&item_id)
+    }
+
+    pub fn on_floor(&self) -> impl Iterator<Item = &GameItem> {
+        self.items.values().filter(|item| matches!(item.place, ItemPlace::Floor { .. }))
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+}
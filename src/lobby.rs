@@ -0,0 +1,422 @@
+// who is where: joining a lobby or game, someone else arriving, someone leaving.
+// these are the dc/gc layouts, pc and bb have wider names and go through as RawData
+use std::io::Cursor;
+use std::io::{Read, Write};
+use byteorder::{ReadBytesExt, WriteBytesExt, LittleEndian};
+
+use crate::packet::{PacketData, ParseError, Language, Field, FieldKind, field};
+
+// player_tag:u32, guildcard:u32, ip:u32, client_id:u32, name[0x10]
+const LOBBY_DATA_SIZE: usize = 0x20;
+// lobby data, inventory (0x34C), then display data (0xD0)
+const LOBBY_ENTRY_SIZE: usize = 0x43C;
+const DISP_OFFSET: usize = 0x36C;
+// within the display data
+const DISP_LEVEL: usize = 0x18;
+const DISP_SECTION_ID: usize = 0x54;
+const DISP_CLASS: usize = 0x55;
+
+const CLASSES: [&str; 12] = [
+    "HUmar", "HUnewearl", "HUcast", "RAmar", "RAcast", "RAcaseal",
+    "FOmarl", "FOnewm", "FOnewearl", "HUcaseal", "FOmar", "RAmarl",
+];
+
+const SECTION_IDS: [&str; 10] = [
+    "Viridia", "Greennill", "Skyly", "Bluefull", "Purplenum",
+    "Pinkal", "Redria", "Oran", "Yellowboze", "Whitill",
+];
+
+pub fn class_name(class: u8) -> &'static str {
+    CLASSES.get(class as usize).cloned().unwrap_or("?")
+}
+
+pub fn section_id_name(section_id: u8) -> &'static str {
+    SECTION_IDS.get(section_id as usize).cloned().unwrap_or("?")
+}
+
+// what a join packet says about one player
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlayerHeader {
+    pub client_id: u8,
+    pub guildcard: u32,
+    pub name: String,
+    // 0x64 only has the lobby data, so these are left for later packets to fill in
+    pub class: Option<u8>,
+    pub section_id: Option<u8>,
+    // as the game shows it, 1 based
+    pub level: Option<u32>,
+}
+
+impl PlayerHeader {
+    fn from_lobby_data(data: &[u8]) -> Result<PlayerHeader, ParseError> {
+        let mut cur = Cursor::new(data);
+        let _player_tag = cur.read_u32::<LittleEndian>()?;
+        let guildcard = cur.read_u32::<LittleEndian>()?;
+        let _ip = cur.read_u32::<LittleEndian>()?;
+        let client_id = cur.read_u32::<LittleEndian>()?;
+        let mut name = [0u8; 0x10];
+        cur.read_exact(&mut name)?;
+
+        let name = name.iter().take_while(|b| **b != 0).map(|b| *b as char).collect::<String>();
+        Ok(PlayerHeader {
+            client_id: client_id as u8,
+            guildcard: guildcard,
+            name: Language::strip(&name).1.to_string(),
+            class: None,
+            section_id: None,
+            level: None,
+        })
+    }
+
+    fn from_lobby_entry(entry: &[u8]) -> Result<PlayerHeader, ParseError> {
+        if entry.len() < LOBBY_ENTRY_SIZE {
+            return Err(ParseError::Truncated);
+        }
+        let disp = &entry[DISP_OFFSET..];
+        let mut player = PlayerHeader::from_lobby_data(&entry[..LOBBY_DATA_SIZE])?;
+        player.level = Some(Cursor::new(&disp[DISP_LEVEL..]).read_u32::<LittleEndian>()?.wrapping_add(1));
+        player.section_id = Some(disp[DISP_SECTION_ID]);
+        player.class = Some(disp[DISP_CLASS]);
+        Ok(player)
+    }
+}
+
+// Kireek (HUcast Redria lv200)
+impl std::fmt::Display for PlayerHeader {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.name)?;
+        if let (Some(class), Some(section_id), Some(level)) = (self.class, self.section_id, self.level) {
+            write!(f, " ({} {} lv{})", class_name(class), section_id_name(section_id), level)?;
+        }
+        Ok(())
+    }
+}
+
+// 0x67 is us joining a lobby, 0x68 someone else arriving in ours and 0x65 someone
+// arriving in our game. flag is how many entries follow. `players` is read out of
+// `entries`, which is what gets sent back out
+#[derive(Debug, Clone)]
+pub struct JoinLobby {
+    pub cmd: u16,
+    pub flag: u32,
+    pub client_id: u8,
+    pub leader_id: u8,
+    pub disable_udp: u8,
+    // 0 based
+    pub lobby: u8,
+    pub block: u16,
+    pub event: u16,
+    pub unused: u32,
+    pub players: Vec<PlayerHeader>,
+    entries: Vec<u8>,
+}
+
+impl JoinLobby {
+    pub const LAYOUT: &'static [Field] = &[
+        field("client_id", FieldKind::U8),
+        field("leader_id", FieldKind::U8),
+        field("disable_udp", FieldKind::U8),
+        field("lobby", FieldKind::U8),
+        field("block", FieldKind::U16),
+        field("event", FieldKind::U16),
+        field("unused", FieldKind::U32),
+        field("entries", FieldKind::Rest),
+    ];
+}
+
+impl PacketData for JoinLobby {
+    fn parse(cmd: u16, flag: u32, data: &Vec<u8>) -> Result<JoinLobby, ParseError> {
+        let mut cur = Cursor::new(data.clone());
+        let client_id = cur.read_u8()?;
+        let leader_id = cur.read_u8()?;
+        let disable_udp = cur.read_u8()?;
+        let lobby = cur.read_u8()?;
+        let block = cur.read_u16::<LittleEndian>()?;
+        let event = cur.read_u16::<LittleEndian>()?;
+        let unused = cur.read_u32::<LittleEndian>()?;
+        let mut entries = Vec::new();
+        cur.read_to_end(&mut entries)?;
+
+        let players = (0..flag as usize)
+            .map(|i| PlayerHeader::from_lobby_entry(entries.get(i * LOBBY_ENTRY_SIZE..).unwrap_or(&[])))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(JoinLobby {
+            cmd: cmd,
+            flag: flag,
+            client_id: client_id,
+            leader_id: leader_id,
+            disable_udp: disable_udp,
+            lobby: lobby,
+            block: block,
+            event: event,
+            unused: unused,
+            players: players,
+            entries: entries,
+        })
+    }
+
+    fn cmd(&self) -> u16 {
+        self.cmd
+    }
+
+    fn flag(&self) -> u32 {
+        self.flag
+    }
+
+    fn as_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.write_u8(self.client_id).unwrap();
+        buf.write_u8(self.leader_id).unwrap();
+        buf.write_u8(self.disable_udp).unwrap();
+        buf.write_u8(self.lobby).unwrap();
+        buf.write_u16::<LittleEndian>(self.block).unwrap();
+        buf.write_u16::<LittleEndian>(self.event).unwrap();
+        buf.write_u32::<LittleEndian>(self.unused).unwrap();
+        buf.write_all(&self.entries).unwrap();
+        buf
+    }
+}
+
+// 0x64, us joining a game. flag is how many players are in it, their lobby data
+// is all there is so `players` has no class or level
+#[derive(Debug, Clone)]
+pub struct JoinGame {
+    pub flag: u32,
+    pub variations: Vec<u8>,
+    pub client_id: u8,
+    pub leader_id: u8,
+    pub disable_udp: u8,
+    pub difficulty: u8,
+    pub battle_mode: u8,
+    pub event: u8,
+    pub section_id: u8,
+    pub challenge_mode: u8,
+    pub rare_seed: u32,
+    pub players: Vec<PlayerHeader>,
+    lobby_data: Vec<u8>,
+    // episode and friends on gc
+    pub trailing: Vec<u8>,
+}
+
+impl JoinGame {
+    pub const LAYOUT: &'static [Field] = &[
+        field("variations", FieldKind::Bytes(0x80)),
+        field("lobby_data", FieldKind::Bytes(4 * LOBBY_DATA_SIZE)),
+        field("client_id", FieldKind::U8),
+        field("leader_id", FieldKind::U8),
+        field("disable_udp", FieldKind::U8),
+        field("difficulty", FieldKind::U8),
+        field("battle_mode", FieldKind::U8),
+        field("event", FieldKind::U8),
+        field("section_id", FieldKind::U8),
+        field("challenge_mode", FieldKind::U8),
+        field("rare_seed", FieldKind::U32),
+        field("trailing", FieldKind::Rest),
+    ];
+}
+
+impl PacketData for JoinGame {
+    fn parse(_cmd: u16, flag: u32, data: &Vec<u8>) -> Result<JoinGame, ParseError> {
+        let mut cur = Cursor::new(data.clone());
+        let mut variations = vec![0u8; 0x80];
+        cur.read_exact(&mut variations)?;
+        let mut lobby_data = vec![0u8; 4 * LOBBY_DATA_SIZE];
+        cur.read_exact(&mut lobby_data)?;
+        let client_id = cur.read_u8()?;
+        let leader_id = cur.read_u8()?;
+        let disable_udp = cur.read_u8()?;
+        let difficulty = cur.read_u8()?;
+        let battle_mode = cur.read_u8()?;
+        let event = cur.read_u8()?;
+        let section_id = cur.read_u8()?;
+        let challenge_mode = cur.read_u8()?;
+        let rare_seed = cur.read_u32::<LittleEndian>()?;
+        let mut trailing = Vec::new();
+        cur.read_to_end(&mut trailing)?;
+
+        // slot n is client id n, whoever isn't there leaves a gap of zeroes
+        let players = lobby_data.chunks(LOBBY_DATA_SIZE)
+            .map(PlayerHeader::from_lobby_data)
+            .filter(|player| !matches!(player, Ok(player) if player.guildcard == 0))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(JoinGame {
+            flag: flag,
+            variations: variations,
+            client_id: client_id,
+            leader_id: leader_id,
+            disable_udp: disable_udp,
+            difficulty: difficulty,
+            battle_mode: battle_mode,
+            event: event,
+            section_id: section_id,
+            challenge_mode: challenge_mode,
+            rare_seed: rare_seed,
+            players: players,
+            lobby_data: lobby_data,
+            trailing: trailing,
+        })
+    }
+
+    fn cmd(&self) -> u16 {
+        0x64
+    }
+
+    fn flag(&self) -> u32 {
+        self.flag
+    }
+
+    fn as_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.write_all(&self.variations).unwrap();
+        buf.write_all(&self.lobby_data).unwrap();
+        buf.write_u8(self.client_id).unwrap();
+        buf.write_u8(self.leader_id).unwrap();
+        buf.write_u8(self.disable_udp).unwrap();
+        buf.write_u8(self.difficulty).unwrap();
+        buf.write_u8(self.battle_mode).unwrap();
+        buf.write_u8(self.event).unwrap();
+        buf.write_u8(self.section_id).unwrap();
+        buf.write_u8(self.challenge_mode).unwrap();
+        buf.write_u32::<LittleEndian>(self.rare_seed).unwrap();
+        buf.write_all(&self.trailing).unwrap();
+        buf
+    }
+}
+
+// 0x66 someone leaving our game, 0x69 someone leaving our lobby
+#[derive(Debug, Clone)]
+pub struct PlayerLeave {
+    pub cmd: u16,
+    pub flag: u32,
+    pub client_id: u8,
+    pub leader_id: u8,
+    pub disable_udp: u8,
+    pub unused: u8,
+    pub trailing: Vec<u8>,
+}
+
+impl PlayerLeave {
+    pub const LAYOUT: &'static [Field] = &[
+        field("client_id", FieldKind::U8),
+        field("leader_id", FieldKind::U8),
+        field("disable_udp", FieldKind::U8),
+        field("unused", FieldKind::U8),
+        field("trailing", FieldKind::Rest),
+    ];
+}
+
+impl PacketData for PlayerLeave {
+    fn parse(cmd: u16, flag: u32, data: &Vec<u8>) -> Result<PlayerLeave, ParseError> {
+        let mut cur = Cursor::new(data.clone());
+        let client_id = cur.read_u8()?;
+        let leader_id = cur.read_u8()?;
+        let disable_udp = cur.read_u8()?;
+        let unused = cur.read_u8()?;
+        let mut trailing = Vec::new();
+        cur.read_to_end(&mut trailing)?;
+        Ok(PlayerLeave {
+            cmd: cmd,
+            flag: flag,
+            client_id: client_id,
+            leader_id: leader_id,
+            disable_udp: disable_udp,
+            unused: unused,
+            trailing: trailing,
+        })
+    }
+
+    fn cmd(&self) -> u16 {
+        self.cmd
+    }
+
+    fn flag(&self) -> u32 {
+        self.flag
+    }
+
+    fn as_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.write_u8(self.client_id).unwrap();
+        buf.write_u8(self.leader_id).unwrap();
+        buf.write_u8(self.disable_udp).unwrap();
+        buf.write_u8(self.unused).unwrap();
+        buf.write_all(&self.trailing).unwrap();
+        buf
+    }
+}
+
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::packet::{Packet, ClientVersion};
+
+    pub fn lobby_data(client_id: u8, guildcard: u32, name: &str) -> Vec<u8> {
+        let mut data = vec![0, 0, 1, 0];
+        data.extend(guildcard.to_le_bytes().iter());
+        data.extend([0u8; 4].iter());
+        data.extend((client_id as u32).to_le_bytes().iter());
+        data.extend(name.bytes().chain(std::iter::repeat(0)).take(0x10));
+        data
+    }
+
+    pub fn lobby_entry(client_id: u8, guildcard: u32, name: &str, class: u8, section_id: u8, level: u32) -> Vec<u8> {
+        let mut entry = lobby_data(client_id, guildcard, name);
+        entry.resize(LOBBY_ENTRY_SIZE, 0);
+        entry[DISP_OFFSET + DISP_LEVEL..DISP_OFFSET + DISP_LEVEL + 4].copy_from_slice(&(level - 1).to_le_bytes());
+        entry[DISP_OFFSET + DISP_SECTION_ID] = section_id;
+        entry[DISP_OFFSET + DISP_CLASS] = class;
+        entry
+    }
+
+    // our client id, lobby 3 block 1, then the entries
+    pub fn join_lobby(client_id: u8, entries: &[Vec<u8>]) -> Vec<u8> {
+        let mut data = vec![client_id, 0, 1, 2, 1, 0, 0, 0, 0, 0, 0, 0];
+        for entry in entries {
+            data.extend(entry.iter());
+        }
+        data
+    }
+
+    #[test]
+    fn lobby_join() {
+        let data = join_lobby(1, &[
+            lobby_entry(0, 42000001, "\tEKireek", 2, 6, 200),
+            lobby_entry(1, 42000002, "Asphodel", 8, 9, 57),
+        ]);
+        let join = match Packet::parse(ClientVersion::Gamecube, 0x67, 2, &data) {
+            Packet::LobbyJoin(join) => join,
+            pkt => panic!("parsed as {:?}", pkt),
+        };
+        assert_eq!((join.client_id, join.lobby, join.block), (1, 2, 1));
+        assert_eq!(join.players, vec![
+            PlayerHeader { client_id: 0, guildcard: 42000001, name: "Kireek".to_string(), class: Some(2), section_id: Some(6), level: Some(200) },
+            PlayerHeader { client_id: 1, guildcard: 42000002, name: "Asphodel".to_string(), class: Some(8), section_id: Some(9), level: Some(57) },
+        ]);
+        assert_eq!(join.players[0].to_string(), "Kireek (HUcast Redria lv200)");
+        assert_eq!(join.as_bytes(), data);
+
+        // the count says there's more than there is
+        assert!(matches!(Packet::parse(ClientVersion::Gamecube, 0x67, 3, &data), Packet::RawData(_)));
+        // and pc names are wider
+        assert!(matches!(Packet::parse(ClientVersion::PC, 0x67, 2, &data), Packet::RawData(_)));
+    }
+
+    #[test]
+    fn game_join() {
+        let mut data = vec![0u8; 0x80];
+        data.extend(lobby_data(0, 42000001, "Kireek"));
+        data.extend(vec![0u8; 2 * LOBBY_DATA_SIZE]);
+        data.extend(lobby_data(3, 42000003, "Ash"));
+        data.extend([3, 0, 1, 2, 0, 0, 5, 0, 0x78, 0x56, 0x34, 0x12, 1, 0, 0, 0].iter());
+        let join = match Packet::parse(ClientVersion::Gamecube, 0x64, 2, &data) {
+            Packet::GameJoin(join) => join,
+            pkt => panic!("parsed as {:?}", pkt),
+        };
+        assert_eq!((join.client_id, join.difficulty, join.section_id, join.rare_seed), (3, 2, 5, 0x12345678));
+        assert_eq!(join.players.iter().map(|p| (p.client_id, p.name.as_str())).collect::<Vec<_>>(), vec![(0, "Kireek"), (3, "Ash")]);
+        assert_eq!(join.players[1].level, None);
+        assert_eq!(join.as_bytes(), data);
+    }
+}
@@ -0,0 +1,301 @@
+// leveled logging, filtered per module and per packet cmd. the filter starts out
+// as whatever the config says and can be changed at runtime with `log <spec>`
+//
+// a spec is a comma separated list of:
+//   info          level for anything without its own
+//   proxy=trace   level for one module
+//   hide=60       drop packet lines for a cmd, show=60 undoes it
+//   only=06       only log packet lines for these cmds, show=all clears hide and only
+
+use std::collections::BTreeSet;
+use std::sync::RwLock;
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    fn parse(s: &str) -> Option<Level> {
+        match s {
+            "error" => Some(Level::Error),
+            "warn" => Some(Level::Warn),
+            "info" => Some(Level::Info),
+            "debug" => Some(Level::Debug),
+            "trace" => Some(Level::Trace),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+            Level::Trace => "trace",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum LogSpecError {
+    UnknownLevel(String),
+    BadCmd(String),
+    UnknownDirective(String),
+}
+
+impl std::fmt::Display for LogSpecError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            LogSpecError::UnknownLevel(level) => write!(f, "unknown level: {}", level),
+            LogSpecError::BadCmd(cmd) => write!(f, "bad cmd: {}", cmd),
+            LogSpecError::UnknownDirective(directive) => write!(f, "unknown directive: {}", directive),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Directive {
+    Level(Level),
+    Module(String, Level),
+    Hide(u16),
+    Show(u16),
+    ShowAll,
+    Only(u16),
+}
+
+fn parse_cmd(s: &str) -> Result<u16, LogSpecError> {
+    u16::from_str_radix(s.trim_start_matches("0x"), 16).map_err(|_| LogSpecError::BadCmd(s.to_string()))
+}
+
+pub fn parse_spec(spec: &str) -> Result<Vec<Directive>, LogSpecError> {
+    spec.split(',')
+        .map(|d| d.trim())
+        .filter(|d| !d.is_empty())
+        .map(|d| {
+            let mut split = d.splitn(2, '=');
+            let key = split.next().unwrap();
+            match (key, split.next()) {
+                (level, None) => Level::parse(level)
+                    .map(Directive::Level)
+                    .ok_or(LogSpecError::UnknownLevel(level.to_string())),
+                ("hide", Some(cmd)) => Ok(Directive::Hide(parse_cmd(cmd)?)),
+                ("show", Some("all")) => Ok(Directive::ShowAll),
+                ("show", Some(cmd)) => Ok(Directive::Show(parse_cmd(cmd)?)),
+                ("only", Some(cmd)) => Ok(Directive::Only(parse_cmd(cmd)?)),
+                (module, Some(level)) if !module.is_empty() => Level::parse(level)
+                    .map(|level| Directive::Module(module.to_string(), level))
+                    .ok_or(LogSpecError::UnknownLevel(level.to_string())),
+                _ => Err(LogSpecError::UnknownDirective(d.to_string())),
+            }
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogFilter {
+    level: Level,
+    modules: Vec<(String, Level)>,
+    hidden: BTreeSet<u16>,
+    // empty means every cmd
+    only: BTreeSet<u16>,
+}
+
+impl LogFilter {
+    pub const fn new() -> LogFilter {
+        LogFilter {
+            level: Level::Info,
+            modules: Vec::new(),
+            hidden: BTreeSet::new(),
+            only: BTreeSet::new(),
+        }
+    }
+
+    pub fn from_spec(spec: &str) -> Result<LogFilter, LogSpecError> {
+        let mut filter = LogFilter::new();
+        filter.apply(&parse_spec(spec)?);
+        Ok(filter)
+    }
+
+    pub fn apply(&mut self, directives: &[Directive]) {
+        for directive in directives {
+            match directive {
+                Directive::Level(level) => self.level = *level,
+                Directive::Module(module, level) => {
+                    self.modules.retain(|(m, _)| m != module);
+                    self.modules.push((module.clone(), *level));
+                },
+                Directive::Hide(cmd) => {
+                    self.hidden.insert(*cmd);
+                },
+                Directive::Show(cmd) => {
+                    self.hidden.remove(cmd);
+                },
+                Directive::ShowAll => {
+                    self.hidden.clear();
+                    self.only.clear();
+                },
+                Directive::Only(cmd) => {
+                    self.only.insert(*cmd);
+                },
+            }
+        }
+    }
+
+    pub fn enabled(&self, level: Level, module: &str) -> bool {
+        let max = self.modules.iter()
+            .filter(|(m, _)| module == m || module.starts_with(&format!("{}::", m)))
+            .map(|(_, level)| *level)
+            .last()
+            .unwrap_or(self.level);
+        level <= max
+    }
+
+    pub fn packet_enabled(&self, level: Level, module: &str, cmd: u16) -> bool {
+        !self.hidden.contains(&cmd)
+            && (self.only.is_empty() || self.only.contains(&cmd))
+            && self.enabled(level, module)
+    }
+}
+
+// back to a spec, `log` shows the filter like this
+impl std::fmt::Display for LogFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.level.name())?;
+        for (module, level) in self.modules.iter() {
+            write!(f, ",{}={}", module, level.name())?;
+        }
+        for cmd in self.hidden.iter() {
+            write!(f, ",hide={:02X}", cmd)?;
+        }
+        for cmd in self.only.iter() {
+            write!(f, ",only={:02X}", cmd)?;
+        }
+        Ok(())
+    }
+}
+
+static FILTER: RwLock<LogFilter> = RwLock::new(LogFilter::new());
+
+pub fn set(filter: LogFilter) {
+    *FILTER.write().unwrap() = filter;
+}
+
+// changes the current filter rather than starting over
+pub fn update(directives: &[Directive]) {
+    FILTER.write().unwrap().apply(directives);
+}
+
+pub fn current() -> LogFilter {
+    FILTER.read().unwrap().clone()
+}
+
+// darkbridge::proxy -> proxy
+pub fn module_name(path: &str) -> &str {
+    path.splitn(2, "::").nth(1).unwrap_or("main")
+}
+
+pub fn enabled(level: Level, path: &str) -> bool {
+    FILTER.read().unwrap().enabled(level, module_name(path))
+}
+
+pub fn packet_enabled(level: Level, path: &str, cmd: u16) -> bool {
+    FILTER.read().unwrap().packet_enabled(level, module_name(path), cmd)
+}
+
+pub fn write(level: Level, path: &str, args: std::fmt::Arguments) {
+    println!("{:5} {}: {}", level.name(), module_name(path), args);
+}
+
+pub fn hexdump(data: &[u8]) -> String {
+    let mut rows = Vec::new();
+    for (i, row) in data.chunks(16).enumerate() {
+        let mut hexbuf = Vec::new();
+        let mut asciibuf = Vec::new();
+        for item in row {
+            hexbuf.push(format!("{:02X}", item));
+            asciibuf.push(format!("{}", if *item > 0x20 && *item < 0x7E {*item as char} else {'.'}));
+        }
+        rows.push(format!("{:04X} | {:47} | {:16} |", i*16, hexbuf.join(" "), asciibuf.join("")));
+    }
+    rows.join("\n")
+}
+
+macro_rules! log {
+    ($level:expr, $($arg:tt)+) => {
+        if $crate::logging::enabled($level, module_path!()) {
+            $crate::logging::write($level, module_path!(), format_args!($($arg)+));
+        }
+    }
+}
+
+// same as log! but also goes through the hide/only cmd filters
+macro_rules! log_packet {
+    ($level:expr, $cmd:expr, $($arg:tt)+) => {
+        if $crate::logging::packet_enabled($level, module_path!(), $cmd) {
+            $crate::logging::write($level, module_path!(), format_args!($($arg)+));
+        }
+    }
+}
+
+macro_rules! error {
+    ($($arg:tt)+) => { log!($crate::logging::Level::Error, $($arg)+) }
+}
+
+macro_rules! warn {
+    ($($arg:tt)+) => { log!($crate::logging::Level::Warn, $($arg)+) }
+}
+
+macro_rules! info {
+    ($($arg:tt)+) => { log!($crate::logging::Level::Info, $($arg)+) }
+}
+
+macro_rules! debug {
+    ($($arg:tt)+) => { log!($crate::logging::Level::Debug, $($arg)+) }
+}
+
+macro_rules! trace {
+    ($($arg:tt)+) => { log!($crate::logging::Level::Trace, $($arg)+) }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spec() {
+        let filter = LogFilter::from_spec("warn, proxy=trace,hide=60,hide=0x62").unwrap();
+        assert!(filter.enabled(Level::Warn, "filters"));
+        assert!(!filter.enabled(Level::Info, "filters"));
+        assert!(filter.enabled(Level::Trace, "proxy"));
+        assert!(!filter.packet_enabled(Level::Debug, "proxy", 0x60));
+        assert!(!filter.packet_enabled(Level::Debug, "proxy", 0x62));
+        assert!(filter.packet_enabled(Level::Debug, "proxy", 0x06));
+
+        assert!(LogFilter::from_spec("loud").is_err());
+        assert!(LogFilter::from_spec("hide=zz").is_err());
+        assert!(LogFilter::from_spec("proxy=loud").is_err());
+    }
+
+    #[test]
+    fn runtime_changes() {
+        let mut filter = LogFilter::from_spec("debug,hide=60").unwrap();
+        filter.apply(&parse_spec("only=06,only=61").unwrap());
+        assert!(filter.packet_enabled(Level::Debug, "proxy", 0x06));
+        assert!(!filter.packet_enabled(Level::Debug, "proxy", 0x1D));
+
+        filter.apply(&parse_spec("show=all,proxy=info").unwrap());
+        assert!(filter.packet_enabled(Level::Debug, "filters", 0x60));
+        assert!(!filter.packet_enabled(Level::Debug, "proxy", 0x60));
+        assert_eq!(filter.to_string(), "debug,proxy=info");
+        assert_eq!(LogFilter::from_spec(&LogFilter::from_spec("warn,hide=60,only=6").unwrap().to_string()).unwrap().to_string(), "warn,hide=60,only=06");
+        assert_eq!(module_name("darkbridge::proxy"), "proxy");
+        assert_eq!(module_name("darkbridge"), "main");
+    }
+}
@@ -1,40 +1,78 @@
 #![feature(iter_array_chunks)]
 
+#[macro_use]
+mod logging;
 mod filters;
 mod proxy;
 mod cipher;
 mod gamecommand;
 mod packet;
+mod lobby;
+mod roster;
+mod gameitems;
 mod commands;
 mod items;
+mod config;
+mod connection;
+mod capture;
+mod chatlog;
+mod replay;
+mod pcap;
+mod dissector;
+#[cfg(test)]
+mod mockserver;
 
 use std::convert::TryInto;
 
 use proxy::Proxy;
+use config::Config;
 
 use std::net::TcpListener;
-use std::net::{SocketAddr, Ipv4Addr};
+use std::thread;
 //use mio::*;
 //use mio::tcp::TcpListener;
 use mio::net::TcpStream;
 
-const PSOPORT: u16 = 9100;
-
 
 // TODO: FEATURES
-// TODO: saved chatlogs
 // TODO: hit fence -> drop hunters report
 // TODO: chat commands
 // TODO: dps meter
 // TODO: handlers for specific events (join game, etc?)
-const TARGET_SERVER: Ipv4Addr = Ipv4Addr::new(149, 56, 167, 128);
 
 fn main() {
+    let config = match Config::from_args(std::env::args().skip(1)) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("config error: {}", err);
+            std::process::exit(1);
+        }
+    };
+    logging::set(config.log.clone());
+    debug!("config: {:?}", config);
+
+    if let Some(path) = config.dissector.clone() {
+        if let Err(err) = std::fs::write(&path, dissector::generate()) {
+            eprintln!("could not write dissector: {}", err);
+            std::process::exit(1);
+        }
+        println!("wrote dissector to {:?}", path);
+        return;
+    }
+
+    if let Some(path) = config.replay.clone() {
+        if let Err(err) = replay::run(path, config) {
+            eprintln!("replay error: {}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     //let listener = TcpListener::bind(("localhost", PSOPORT)).unwrap();
     //let listener = TcpListener::bind("127.0.0.1:9100").unwrap();
     //let listener = TcpListener::bind("0.0.0.0:9100").unwrap();
-    let listener = TcpListener::bind(&SocketAddr::from((Ipv4Addr::new(0,0,0,0), PSOPORT))).unwrap();
-    println!("l: {:?}", listener);
+    let listener = TcpListener::bind(&config.listen).unwrap();
+    info!("listening on {:?}", listener);
     
     /*let poll = Poll::new().unwrap();
 
@@ -62,15 +100,26 @@ fn main() {
 
 
     
+    let mut session = 0;
     loop {
         match listener.accept() {
             Ok((socket, addr)) => {
-                println!("recv! {:?} {:?}", socket, addr);
-                let mut proxy = Proxy::new(socket);
-                proxy.run();
+                session += 1;
+                info!("[{}] new session: {:?} {:?}", session, socket, addr);
+                let config = config.clone();
+                let id = session;
+                thread::Builder::new()
+                    .name(format!("session-{}", id))
+                    .spawn(move || {
+                        match Proxy::new(id, socket, config).and_then(|mut proxy| proxy.run()) {
+                            Ok(()) => info!("[{}] session ended", id),
+                            Err(err) => warn!("[{}] session ended: {}", id, err),
+                        }
+                    })
+                    .unwrap();
             }
             Err(e) => {
-                println!("err: {:?}", e);
+                error!("accept failed: {:?}", e);
             }
         }
     }
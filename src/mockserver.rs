@@ -0,0 +1,234 @@
+// stand-ins for a ship server and a gamecube, enough of the gc login flow to
+// drive a real Proxy over loopback
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream, SocketAddr};
+use std::sync::mpsc::{channel, Receiver};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::cipher::{Cipher, PacketCipher};
+use crate::packet::{Packet, ClientVersion, EncryptionKeys, AllowDenyAccess, Redirect};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+// one end of a gc connection, blocking and with the real cipher once keys are exchanged
+pub struct PsoStream {
+    pub sock: TcpStream,
+    send_cipher: Option<Cipher>,
+    recv_cipher: Option<Cipher>,
+}
+
+impl PsoStream {
+    pub fn new(sock: TcpStream) -> PsoStream {
+        sock.set_read_timeout(Some(TIMEOUT)).unwrap();
+        PsoStream {
+            sock: sock,
+            send_cipher: None,
+            recv_cipher: None,
+        }
+    }
+
+    pub fn send(&mut self, pkt: &Packet) -> std::io::Result<()> {
+        let mut buf = pkt.as_bytes(ClientVersion::Gamecube);
+        if let Some(ref mut cipher) = self.send_cipher {
+            cipher.apply(&mut buf);
+        }
+        self.sock.write_all(&buf)
+    }
+
+    pub fn recv(&mut self) -> std::io::Result<Packet> {
+        let version = ClientVersion::Gamecube;
+        let mut header_buf = vec![0u8; version.header_size()];
+        self.sock.read_exact(&mut header_buf)?;
+        if let Some(ref mut cipher) = self.recv_cipher {
+            cipher.apply(&mut header_buf);
+        }
+        let header = version.read_header(&header_buf)?;
+
+        let mut data = vec![0u8; version.wire_len(&header) - version.header_size()];
+        self.sock.read_exact(&mut data)?;
+        if let Some(ref mut cipher) = self.recv_cipher {
+            cipher.apply(&mut data);
+        }
+        Ok(Packet::parse(version, header.cmd, header.flag, &data))
+    }
+
+    // server side: send the keys in the clear then switch both directions over
+    pub fn server_handshake(&mut self, cmd: u16, server_seed: u32, client_seed: u32) -> std::io::Result<()> {
+        self.send(&Packet::EncryptionKeys(EncryptionKeys {
+            cmd: cmd,
+            flag: 0,
+            welcome_msg: b"DreamCast Lobby Server. Copyright SEGA Enterprises. 1999".iter().cloned().chain(std::iter::repeat(0)).take(0x40).collect(),
+            client_seed: client_seed,
+            server_seed: server_seed,
+            secret_msg: b"mock server\0".to_vec(),
+        }))?;
+        self.send_cipher = Some(Cipher::new(server_seed));
+        self.recv_cipher = Some(Cipher::new(client_seed));
+        Ok(())
+    }
+
+    // client side: wait for the keys and switch both directions over
+    pub fn client_handshake(&mut self) -> std::io::Result<EncryptionKeys> {
+        match self.recv()? {
+            Packet::EncryptionKeys(keys) => {
+                self.send_cipher = Some(Cipher::new(keys.client_seed));
+                self.recv_cipher = Some(Cipher::new(keys.server_seed));
+                Ok(keys)
+            },
+            pkt => panic!("expected encryption keys, got {:?}", pkt),
+        }
+    }
+}
+
+pub struct MockServer {
+    pub addr: SocketAddr,
+    // everything the server received, in order
+    pub received: Receiver<Packet>,
+    handle: JoinHandle<()>,
+}
+
+impl MockServer {
+    fn spawn<F: FnOnce(PsoStream, &dyn Fn(Packet)) + Send + 'static>(script: F) -> MockServer {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = channel();
+        let handle = thread::spawn(move || {
+            let (sock, _) = listener.accept().unwrap();
+            script(PsoStream::new(sock), &|pkt| { let _ = tx.send(pkt); });
+        });
+
+        MockServer {
+            addr: addr,
+            received: rx,
+            handle: handle,
+        }
+    }
+
+    // 0x02 keys, waits for a login, allows it and sends the client off to `ship`
+    pub fn login(server_seed: u32, client_seed: u32, ship: SocketAddr) -> MockServer {
+        MockServer::spawn(move |mut stream, received| {
+            stream.server_handshake(0x02, server_seed, client_seed).unwrap();
+            received(stream.recv().unwrap());
+            stream.send(&Packet::AllowDenyAccess(AllowDenyAccess { allow: 1, trailing: Vec::new() })).unwrap();
+
+            let ip = match ship.ip() {
+                std::net::IpAddr::V4(ip) => ip.octets(),
+                _ => unreachable!(),
+            };
+            stream.send(&Packet::Redirect(Redirect { flag: 0, ip: ip, port: ship.port(), trailing: vec![0, 0] })).unwrap();
+
+            // hang around until the proxy drops us for the ship
+            while let Ok(pkt) = stream.recv() {
+                received(pkt);
+            }
+        })
+    }
+
+    // 0x17 keys and then takes whatever it is sent until the connection closes
+    pub fn ship(server_seed: u32, client_seed: u32) -> MockServer {
+        MockServer::spawn(move |mut stream, received| {
+            stream.server_handshake(0x17, server_seed, client_seed).unwrap();
+            while let Ok(pkt) = stream.recv() {
+                received(pkt);
+            }
+        })
+    }
+
+    pub fn next(&self) -> Packet {
+        self.received.recv_timeout(TIMEOUT).expect("mock server got nothing")
+    }
+
+    pub fn join(self) {
+        self.handle.join().unwrap();
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::proxy::Proxy;
+    use crate::packet::RawData;
+    use crate::gamecommand::GameCommandAction;
+
+    // a fake gamecube connected through a real proxy to a login server that redirects to a ship
+    #[test]
+    fn login_redirect_and_commands() {
+        let ship = MockServer::ship(0x11111111, 0x22222222);
+        let login = MockServer::login(0x33333333, 0x44444444, ship.addr);
+
+        let config = Config::for_tests(&["-u", &login.addr.to_string(), "-l", "127.0.0.1:0", "-r", "127.0.0.1"]);
+
+        let gc_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut gamecube = PsoStream::new(TcpStream::connect(gc_listener.local_addr().unwrap()).unwrap());
+        let (sock, _) = gc_listener.accept().unwrap();
+        let session = std::process::id();
+        let proxy = thread::spawn(move || {
+            Proxy::new(session, sock, config).and_then(|mut proxy| proxy.run())
+        });
+
+        // login server, everything after the keys goes through the cipher both ways
+        let keys = gamecube.client_handshake().unwrap();
+        assert_eq!((keys.server_seed, keys.client_seed), (0x33333333, 0x44444444));
+        let login_pkt = RawData { cmd: 0x93, flag: 0, data: (0..0x20).collect() };
+        gamecube.send(&Packet::RawData(login_pkt.clone())).unwrap();
+        match login.next() {
+            Packet::RawData(raw) => assert_eq!((raw.cmd, raw.data), (login_pkt.cmd, login_pkt.data)),
+            pkt => panic!("login server got {:?}", pkt),
+        }
+        match gamecube.recv().unwrap() {
+            Packet::AllowDenyAccess(access) => assert_eq!(access.allow, 1),
+            pkt => panic!("gamecube got {:?}", pkt),
+        }
+
+        // the redirect has to point back at the proxy rather than the ship
+        let redirect = match gamecube.recv().unwrap() {
+            Packet::Redirect(redirect) => redirect,
+            pkt => panic!("gamecube got {:?}", pkt),
+        };
+        assert_eq!(redirect.ip, [127, 0, 0, 1]);
+        assert_ne!(redirect.port, ship.addr.port());
+        let mut gamecube = PsoStream::new(TcpStream::connect(SocketAddr::from((redirect.ip, redirect.port))).unwrap());
+
+        // new keys from the ship, the proxy must have thrown out the login server's ciphers
+        let keys = gamecube.client_handshake().unwrap();
+        assert_eq!((keys.server_seed, keys.client_seed), (0x11111111, 0x22222222));
+        gamecube.send(&Packet::RawData(RawData { cmd: 0x60, flag: 0, data: vec![0x1F, 0x02, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00] })).unwrap();
+        match ship.next() {
+            Packet::GameCommand(cmd) => match cmd.cmd {
+                GameCommandAction::PlayerArea(area) => assert_eq!(area.floor, 4),
+                action => panic!("ship got {:?}", action),
+            },
+            pkt => panic!("ship got {:?}", pkt),
+        }
+
+        // commands written to the session's pipe go out to whichever side they name
+        let mut pipe = std::fs::OpenOptions::new().write(true).open(format!("/tmp/darkbridge.{}", session)).unwrap();
+        pipe.write_all(b"raw server 1d 00 01020304\nraw client 1d 00 05060708\n").unwrap();
+        match ship.next() {
+            Packet::RawData(raw) => assert_eq!((raw.cmd, raw.data), (0x1D, vec![1, 2, 3, 4])),
+            pkt => panic!("ship got {:?}", pkt),
+        }
+        // each one is confirmed to the gamecube only
+        let confirmed = |pkt: Packet| match pkt {
+            Packet::ChatMessage(chat) => assert_eq!((chat.name(), chat.message().as_str()), ("darkbridge", "packet sent")),
+            pkt => panic!("gamecube got {:?}", pkt),
+        };
+        confirmed(gamecube.recv().unwrap());
+        match gamecube.recv().unwrap() {
+            Packet::RawData(raw) => assert_eq!((raw.cmd, raw.data), (0x1D, vec![5, 6, 7, 8])),
+            pkt => panic!("gamecube got {:?}", pkt),
+        }
+        confirmed(gamecube.recv().unwrap());
+
+        // the gamecube hanging up ends the session cleanly
+        drop(pipe);
+        drop(gamecube);
+        assert!(proxy.join().unwrap().is_ok());
+        ship.join();
+        login.join();
+    }
+}
@@ -1,126 +1,381 @@
 use std::io::Cursor;
-use std::io::{Read, Write, BufRead, Seek, SeekFrom};
+use std::io::{Read, Write, Seek, SeekFrom};
 use byteorder::{ReadBytesExt, WriteBytesExt, LittleEndian, BigEndian};
+use serde::Deserialize;
 use crate::gamecommand::*;
+use crate::lobby::{JoinLobby, JoinGame, PlayerLeave};
 
+// which pso client a session is talking to, this decides how packet headers look on the wire
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub enum ClientVersion {
+    #[serde(rename = "dc")]
+    Dreamcast,
+    #[serde(rename = "pc")]
+    PC,
+    #[serde(rename = "gc")]
+    Gamecube,
+    #[serde(rename = "bb")]
+    BlueBurst,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PacketHeader {
+    pub cmd: u16,
+    pub flag: u32,
+    // header plus body, not counting any padding
+    pub len: u16,
+}
+
+impl ClientVersion {
+    pub fn header_size(&self) -> usize {
+        match self {
+            ClientVersion::BlueBurst => 8,
+            _ => 4,
+        }
+    }
+
+    // dc and gc text is a byte a character, pc and bb are utf-16
+    pub fn narrow_text(&self) -> bool {
+        match self {
+            ClientVersion::Dreamcast | ClientVersion::Gamecube => true,
+            ClientVersion::PC | ClientVersion::BlueBurst => false,
+        }
+    }
+
+    // packets are padded out to this on the wire
+    pub fn alignment(&self) -> usize {
+        match self {
+            ClientVersion::BlueBurst => 8,
+            _ => 4,
+        }
+    }
+
+    // bytes a packet with this header takes up on the wire
+    pub fn wire_len(&self, header: &PacketHeader) -> usize {
+        let align = self.alignment();
+        (header.len as usize + align - 1) / align * align
+    }
+
+    // dc/gc: cmd:u8 flag:u8 len:u16, pc: len:u16 cmd:u8 flag:u8, bb: len:u16 cmd:u16 flag:u32
+    pub fn read_header(&self, buf: &[u8]) -> std::io::Result<PacketHeader> {
+        let mut cur = Cursor::new(buf);
+        Ok(match self {
+            ClientVersion::Dreamcast | ClientVersion::Gamecube => {
+                let cmd = cur.read_u8()? as u16;
+                let flag = cur.read_u8()? as u32;
+                let len = cur.read_u16::<LittleEndian>()?;
+                PacketHeader { cmd, flag, len }
+            },
+            ClientVersion::PC => {
+                let len = cur.read_u16::<LittleEndian>()?;
+                let cmd = cur.read_u8()? as u16;
+                let flag = cur.read_u8()? as u32;
+                PacketHeader { cmd, flag, len }
+            },
+            ClientVersion::BlueBurst => {
+                let len = cur.read_u16::<LittleEndian>()?;
+                let cmd = cur.read_u16::<LittleEndian>()?;
+                let flag = cur.read_u32::<LittleEndian>()?;
+                PacketHeader { cmd, flag, len }
+            },
+        })
+    }
+
+    pub fn write_header(&self, header: &PacketHeader) -> Vec<u8> {
+        let mut buf: Vec<u8> = Vec::new();
+        match self {
+            ClientVersion::Dreamcast | ClientVersion::Gamecube => {
+                buf.write_u8(header.cmd as u8).unwrap();
+                buf.write_u8(header.flag as u8).unwrap();
+                buf.write_u16::<LittleEndian>(header.len).unwrap();
+            },
+            ClientVersion::PC => {
+                buf.write_u16::<LittleEndian>(header.len).unwrap();
+                buf.write_u8(header.cmd as u8).unwrap();
+                buf.write_u8(header.flag as u8).unwrap();
+            },
+            ClientVersion::BlueBurst => {
+                buf.write_u16::<LittleEndian>(header.len).unwrap();
+                buf.write_u16::<LittleEndian>(header.cmd).unwrap();
+                buf.write_u32::<LittleEndian>(header.flag).unwrap();
+            },
+        }
+        buf
+    }
+
+    // header + body + padding, ready to be encrypted
+    pub fn frame(&self, cmd: u16, flag: u32, body: &[u8]) -> Vec<u8> {
+        let mut header = PacketHeader {
+            cmd: cmd,
+            flag: flag,
+            len: (self.header_size() + body.len()) as u16,
+        };
+        let wire_len = self.wire_len(&header);
+        // only bb leaves the padding out of the length
+        if *self != ClientVersion::BlueBurst {
+            header.len = wire_len as u16;
+        }
+        let mut buf = self.write_header(&header);
+        buf.extend_from_slice(body);
+        buf.resize(wire_len, 0);
+        buf
+    }
+}
+
+#[derive(Debug)]
+pub enum ParseError {
+    Truncated,
+    // bytes left over after every known field was read
+    TrailingData(usize),
+    // utf-16 that doesn't decode
+    BadText,
+    Io(std::io::Error),
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ParseError::Truncated => write!(f, "truncated"),
+            ParseError::TrailingData(len) => write!(f, "{} bytes of trailing data", len),
+            ParseError::BadText => write!(f, "bad utf-16 text"),
+            ParseError::Io(err) => write!(f, "io error: {}", err),
+        }
+    }
+}
+
+impl From<std::io::Error> for ParseError {
+    fn from(err: std::io::Error) -> ParseError {
+        if err.kind() == std::io::ErrorKind::UnexpectedEof {
+            ParseError::Truncated
+        }
+        else {
+            ParseError::Io(err)
+        }
+    }
+}
+
+// how a field sits in a packet body, for tools that need the layout without parsing (the wireshark dissector)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FieldKind {
+    U8,
+    U16,
+    U32,
+    U32BE,
+    F32,
+    Ipv4,
+    Str(usize),
+    Bytes(usize),
+    // whatever is left of the body
+    Rest,
+    RestStr,
+}
+
+impl FieldKind {
+    // None for the ones that take up the rest of the body
+    pub fn size(&self) -> Option<usize> {
+        match self {
+            FieldKind::U8 => Some(1),
+            FieldKind::U16 => Some(2),
+            FieldKind::U32 | FieldKind::U32BE | FieldKind::F32 | FieldKind::Ipv4 => Some(4),
+            FieldKind::Str(len) | FieldKind::Bytes(len) => Some(*len),
+            FieldKind::Rest | FieldKind::RestStr => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Field {
+    pub name: &'static str,
+    pub kind: FieldKind,
+}
+
+pub const fn field(name: &'static str, kind: FieldKind) -> Field {
+    Field {
+        name: name,
+        kind: kind,
+    }
+}
+
+// as_bytes is just the body, the session's ClientVersion puts the header on
 pub trait PacketData {
-    fn parse(cmd: u8, flag: u8, data: &Vec<u8>) -> Self where Self: Sized;
+    fn parse(cmd: u16, flag: u32, data: &Vec<u8>) -> Result<Self, ParseError> where Self: Sized;
+    fn cmd(&self) -> u16;
+    fn flag(&self) -> u32;
     fn as_bytes(&self) -> Vec<u8>;
 }
 
 #[derive(Debug, Clone)]
 pub struct Redirect {
+    pub flag: u32,
     pub ip: [u8; 4],
     pub port: u16,
+    // usually two bytes of padding
+    pub trailing: Vec<u8>,
+}
+
+impl Redirect {
+    pub const LAYOUT: &'static [Field] = &[
+        field("ip", FieldKind::Ipv4),
+        field("port", FieldKind::U16),
+        field("trailing", FieldKind::Rest),
+    ];
 }
 
 impl PacketData for Redirect {
-    fn parse(cmd: u8, flag: u8, data: &Vec<u8>) -> Redirect {
+    fn parse(_cmd: u16, flag: u32, data: &Vec<u8>) -> Result<Redirect, ParseError> {
         let mut cur = Cursor::new(data.clone());
-        cur.set_position(0x04);
-        let port = cur.read_u16::<LittleEndian>().unwrap();
-        Redirect {
-            ip: [data[0x00], data[0x01], data[0x02], data[0x03]],
+        let mut ip = [0u8; 4];
+        cur.read_exact(&mut ip)?;
+        let port = cur.read_u16::<LittleEndian>()?;
+        let mut trailing = Vec::new();
+        cur.read_to_end(&mut trailing)?;
+        Ok(Redirect {
+            flag: flag,
+            ip: ip,
             port: port,
-        }
+            trailing: trailing,
+        })
+    }
+
+    fn cmd(&self) -> u16 {
+        0x19
+    }
+
+    fn flag(&self) -> u32 {
+        self.flag
     }
 
     fn as_bytes(&self) -> Vec<u8> {
         let mut buf: Vec<u8> = Vec::new();
-        buf.write_u16::<LittleEndian>(0x19).unwrap();
-        buf.write_u16::<LittleEndian>(0x0C).unwrap();
         buf.write_u8(self.ip[0]).unwrap();
         buf.write_u8(self.ip[1]).unwrap();
         buf.write_u8(self.ip[2]).unwrap();
         buf.write_u8(self.ip[3]).unwrap();
         buf.write_u16::<LittleEndian>(self.port).unwrap();
-        buf.write_u16::<LittleEndian>(0x00).unwrap();
+        buf.write_all(&self.trailing).unwrap();
         buf
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct RawData {
-    pub cmd: u8,
-    pub flag: u8,
-    pub len: u16,
+    pub cmd: u16,
+    pub flag: u32,
     pub data: Vec<u8>
 }
 
+impl RawData {
+    pub const LAYOUT: &'static [Field] = &[
+        field("data", FieldKind::Rest),
+    ];
+}
+
 impl PacketData for RawData {
-    fn parse(cmd: u8, flag: u8, data: &Vec<u8>) -> RawData {
-        RawData {
+    fn parse(cmd: u16, flag: u32, data: &Vec<u8>) -> Result<RawData, ParseError> {
+        Ok(RawData {
             cmd: cmd,
             flag: flag,
-            len: (data.len() + 4) as u16,
             data: data.clone()
-        }
+        })
+    }
+
+    fn cmd(&self) -> u16 {
+        self.cmd
+    }
+
+    fn flag(&self) -> u32 {
+        self.flag
     }
 
     fn as_bytes(&self) -> Vec<u8> {
-        let mut buf: Vec<u8> = Vec::new();
-        buf.write_u8(self.cmd).unwrap();
-        buf.write_u8(self.flag).unwrap();
-        buf.write_u16::<LittleEndian>(self.len).unwrap();
-        buf.extend(&self.data);
-        buf
+        self.data.clone()
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct AllowDenyAccess{
-    pub allow: u8
+    pub allow: u32,
+    // normally empty
+    pub trailing: Vec<u8>,
+}
+
+impl AllowDenyAccess {
+    // allow is the header's flag
+    pub const LAYOUT: &'static [Field] = &[
+        field("trailing", FieldKind::Rest),
+    ];
 }
 
 impl PacketData for AllowDenyAccess {
-    fn parse(cmd: u8, flag: u8, data: &Vec<u8>) -> AllowDenyAccess {
-        AllowDenyAccess {
+    fn parse(_cmd: u16, flag: u32, data: &Vec<u8>) -> Result<AllowDenyAccess, ParseError> {
+        Ok(AllowDenyAccess {
             allow: flag,
-        }
+            trailing: data.clone(),
+        })
+    }
+
+    fn cmd(&self) -> u16 {
+        0x9A
+    }
+
+    fn flag(&self) -> u32 {
+        self.allow
     }
 
     fn as_bytes(&self) -> Vec<u8> {
-        let mut buf: Vec<u8> = Vec::new();
-        buf.write_u8(0x9A).unwrap();
-        buf.write_u8(self.allow).unwrap();
-        buf.write_u16::<LittleEndian>(0x04).unwrap();
-        buf
+        self.trailing.clone()
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct EncryptionKeys {
     //welcome_msg: [u8; 0x40],
-    pub cmd: u8,
+    pub cmd: u16,
+    pub flag: u32,
     pub welcome_msg: Vec<u8>,
     pub client_seed: u32,
     pub server_seed: u32,
     pub secret_msg: Vec<u8>,
 }
 
+impl EncryptionKeys {
+    pub const LAYOUT: &'static [Field] = &[
+        field("welcome_msg", FieldKind::Str(0x40)),
+        field("server_seed", FieldKind::U32),
+        field("client_seed", FieldKind::U32),
+        field("secret_msg", FieldKind::RestStr),
+    ];
+}
+
 impl PacketData for EncryptionKeys {
-    fn parse(cmd: u8, flag: u8, data: &Vec<u8>) -> EncryptionKeys {
+    fn parse(cmd: u16, flag: u32, data: &Vec<u8>) -> Result<EncryptionKeys, ParseError> {
         let mut cur = Cursor::new(data.clone());
         let mut msg = vec![0u8; 0x40];
-        cur.read(&mut msg).unwrap();
-        let sseed = cur.read_u32::<LittleEndian>().unwrap();
-        let cseed = cur.read_u32::<LittleEndian>().unwrap();
+        cur.read_exact(&mut msg)?;
+        let sseed = cur.read_u32::<LittleEndian>()?;
+        let cseed = cur.read_u32::<LittleEndian>()?;
         let mut secret_msg = Vec::new();
-        cur.read_to_end(&mut secret_msg).unwrap();
-        EncryptionKeys {
+        cur.read_to_end(&mut secret_msg)?;
+        Ok(EncryptionKeys {
             cmd: cmd,
+            flag: flag,
             welcome_msg: msg,
             client_seed: cseed,
             server_seed: sseed,
             secret_msg: secret_msg,
-        }
+        })
+    }
+
+    fn cmd(&self) -> u16 {
+        self.cmd
+    }
+
+    fn flag(&self) -> u32 {
+        self.flag
     }
 
     fn as_bytes(&self) -> Vec<u8> {
         let mut buf: Vec<u8> = Vec::new();
-        buf.write_u8(self.cmd).unwrap();
-        buf.write_u8(0).unwrap();
-        buf.write_u16::<LittleEndian>(0x4C + self.secret_msg.len() as u16).unwrap();
         buf.write(&self.welcome_msg).unwrap();
         buf.write_u32::<LittleEndian>(self.server_seed).unwrap();
         buf.write_u32::<LittleEndian>(self.client_seed).unwrap();
@@ -129,6 +384,54 @@ impl PacketData for EncryptionKeys {
     }
 }
 
+// blue burst's 0x03, the seeds are 48 bytes instead of a u32
+#[derive(Debug, Clone)]
+pub struct BBEncryptionKeys {
+    pub flag: u32,
+    pub copyright: Vec<u8>,
+    pub server_key: Vec<u8>,
+    pub client_key: Vec<u8>,
+    pub secret_msg: Vec<u8>,
+}
+
+impl PacketData for BBEncryptionKeys {
+    fn parse(_cmd: u16, flag: u32, data: &Vec<u8>) -> Result<BBEncryptionKeys, ParseError> {
+        let mut cur = Cursor::new(data.clone());
+        let mut copyright = vec![0u8; 0x60];
+        cur.read_exact(&mut copyright)?;
+        let mut server_key = vec![0u8; 0x30];
+        cur.read_exact(&mut server_key)?;
+        let mut client_key = vec![0u8; 0x30];
+        cur.read_exact(&mut client_key)?;
+        let mut secret_msg = Vec::new();
+        cur.read_to_end(&mut secret_msg)?;
+        Ok(BBEncryptionKeys {
+            flag: flag,
+            copyright: copyright,
+            server_key: server_key,
+            client_key: client_key,
+            secret_msg: secret_msg,
+        })
+    }
+
+    fn cmd(&self) -> u16 {
+        0x03
+    }
+
+    fn flag(&self) -> u32 {
+        self.flag
+    }
+
+    fn as_bytes(&self) -> Vec<u8> {
+        let mut buf: Vec<u8> = Vec::new();
+        buf.write_all(&self.copyright).unwrap();
+        buf.write_all(&self.server_key).unwrap();
+        buf.write_all(&self.client_key).unwrap();
+        buf.write_all(&self.secret_msg).unwrap();
+        buf
+    }
+}
+
 
 
 //0000 | 06 00 1C 00 00 00 00 00 EC 36 32 A0 41 73 70 68 | .........62.Asph |
@@ -143,77 +446,274 @@ impl PacketData for EncryptionKeys {
 // 4A 31 32 33 34 35 36 37 38 00
 
 
+// the \tE / \tJ that starts a name or message, it picks the text encoding
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Language {
+    English,
+    Japanese,
+}
+
+impl Language {
+    fn marker(&self) -> &'static str {
+        match self {
+            Language::English => "\tE",
+            Language::Japanese => "\tJ",
+        }
+    }
+
+    // splits a leading marker off `text`
+    pub fn strip(text: &str) -> (Option<Language>, &str) {
+        if let Some(rest) = text.strip_prefix("\tE") {
+            (Some(Language::English), rest)
+        }
+        else if let Some(rest) = text.strip_prefix("\tJ") {
+            (Some(Language::Japanese), rest)
+        }
+        else {
+            (None, text)
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChatText {
+    Text(String),
+    // \tC followed by the color, 0-9 and a handful of letters
+    Color(char),
+}
+
+impl ChatText {
+    pub fn parse(text: &str) -> Vec<ChatText> {
+        let chars = text.chars().collect::<Vec<_>>();
+        let mut out = Vec::new();
+        let mut plain = String::new();
+        let mut i = 0;
+        while i < chars.len() {
+            if chars[i] == '\t' && chars.get(i+1) == Some(&'C') && i + 2 < chars.len() {
+                if !plain.is_empty() {
+                    out.push(ChatText::Text(std::mem::take(&mut plain)));
+                }
+                out.push(ChatText::Color(chars[i+2]));
+                i += 3;
+            }
+            else {
+                plain.push(chars[i]);
+                i += 1;
+            }
+        }
+        if !plain.is_empty() {
+            out.push(ChatText::Text(plain));
+        }
+        out
+    }
+}
+
+// who a message is from, only there on what the server sends out
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChatSender {
+    pub language: Option<Language>,
+    pub name: String,
+}
+
+// unused:u32, guildcard:u32, then nul terminated text:
+//   [\tE]name\t\tEmessage   from the server
+//   \tEmessage             from the client
+// the message can change color partway with \tC<color>. dc and gc text is kept as
+// latin-1 so anything, including \tJ shift-jis, goes back out byte for byte. pc and bb
+// text is utf-16, which version it is only matters on the way in and out
 #[derive(Debug, Clone)]
 pub struct ChatMessage {
-    //pub guildcard: u32,
-    raw: Vec<u8>,
-    //pub character: String,
-    pub message: String,
+    pub cmd: u16,
+    pub flag: u32,
+    pub unused: u32,
+    pub guildcard: u32,
+    pub sender: Option<ChatSender>,
+    pub language: Option<Language>,
+    pub text: Vec<ChatText>,
+    // the nul and whatever padding came after it, None for one we built which just
+    // gets a nul as wide as its text
+    trailing: Option<Vec<u8>>,
 }
 
 impl ChatMessage {
-    fn parse(_cmd: u8, _flag: u8, data: &Vec<u8>) -> ChatMessage {
-        let mut cur = Cursor::new(data.clone());
-        /*
-        let _ = cur.read_u32::<BigEndian>().unwrap();
-        let _guildcard = cur.read_u32::<BigEndian>().unwrap();
-
-        let mut raw_character = Vec::new();
-        cur.read_until(0x09, &mut raw_character).unwrap();
-        let character = String::from_utf8_lossy(&raw_character).into();
-
-        cur.seek(SeekFrom::Current(2)).unwrap();
-
-        let mut raw_message = Vec::new();
-        cur.read_until(0x09, &mut raw_message).unwrap();
-        let message = String::from_utf8_lossy(&raw_message).into();
-         */
-
-        let mut raw_message = Vec::new();
-        let mut message = String::new();
-        while let Ok(k) = cur.read_until(0x09, &mut raw_message) {
-            if k == 0 {
-                break
-            }
-            message = String::from_utf8_lossy(&raw_message).into();
-            raw_message.clear();
+    pub const LAYOUT: &'static [Field] = &[
+        field("unused", FieldKind::U32),
+        field("guildcard", FieldKind::U32),
+        field("message", FieldKind::RestStr),
+    ];
+
+    fn new(cmd: u16, sender: Option<ChatSender>, text: &str) -> ChatMessage {
+        ChatMessage {
+            cmd: cmd,
+            flag: 0,
+            unused: 0,
+            guildcard: 0,
+            sender: sender,
+            language: Some(Language::English),
+            text: ChatText::parse(text),
+            trailing: None,
         }
+    }
+
+    // what the client sends when the player says something, to go to the server
+    pub fn from_player(text: &str) -> ChatMessage {
+        ChatMessage::new(0x06, None, text)
+    }
 
-        let message = message
-            .chars()
-            .skip(1)
-            .filter(|c| *c != '\0')
-            .collect();
-        dbg!(&message);
+    // what the server sends out, to go to the gamecube only and show up as said by `name`
+    pub fn to_player(name: &str, text: &str) -> ChatMessage {
+        ChatMessage::new(0x06, Some(ChatSender {
+            language: Some(Language::English),
+            name: name.to_string(),
+        }), text)
+    }
 
-        ChatMessage {
-            //guildcard,
-            raw: data.clone(),
-            //character,
-            message,
+    // 0x01, a box in the middle of the screen the player has to close
+    pub fn message_box(text: &str) -> ChatMessage {
+        ChatMessage::new(0x01, None, text)
+    }
+
+    // 0x11, the small text at the bottom of the screen
+    pub fn info(text: &str) -> ChatMessage {
+        ChatMessage::new(0x11, None, text)
+    }
+
+    pub fn name(&self) -> &str {
+        self.sender.as_ref().map(|s| s.name.as_str()).unwrap_or("")
+    }
+
+    // the message without any color codes
+    pub fn message(&self) -> String {
+        self.text.iter()
+            .filter_map(|t| match t {
+                ChatText::Text(text) => Some(text.as_str()),
+                ChatText::Color(_) => None,
+            })
+            .collect()
+    }
+}
+
+impl ChatMessage {
+    // pc and bb
+    pub fn parse_wide(cmd: u16, flag: u32, data: &Vec<u8>) -> Result<ChatMessage, ParseError> {
+        ChatMessage::decode(cmd, flag, data, true)
+    }
+
+    pub fn as_wide_bytes(&self) -> Vec<u8> {
+        self.encode(true)
+    }
+
+    fn decode(cmd: u16, flag: u32, data: &Vec<u8>, wide: bool) -> Result<ChatMessage, ParseError> {
+        let mut cur = Cursor::new(data);
+        let unused = cur.read_u32::<LittleEndian>()?;
+        let guildcard = cur.read_u32::<LittleEndian>()?;
+
+        let (text, end) = if wide {
+            let units = data[8..].chunks_exact(2)
+                .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                .take_while(|u| *u != 0)
+                .collect::<Vec<_>>();
+            (String::from_utf16(&units).map_err(|_| ParseError::BadText)?, 8 + units.len() * 2)
         }
+        else {
+            let end = data[8..].iter().position(|b| *b == 0).map(|p| p + 8).unwrap_or(data.len());
+            (data[8..end].iter().map(|b| *b as char).collect::<String>(), end)
+        };
+
+        // the name ends at the tab before the message's language marker
+        let (sender, message) = match text.find("\t\t") {
+            Some(i) => {
+                let (language, name) = Language::strip(&text[..i]);
+                (Some(ChatSender { language: language, name: name.to_string() }), &text[i+1..])
+            },
+            None => (None, text.as_str()),
+        };
+        let (language, message) = Language::strip(message);
+
+        Ok(ChatMessage {
+            cmd: cmd,
+            flag: flag,
+            unused: unused,
+            guildcard: guildcard,
+            sender: sender,
+            language: language,
+            text: ChatText::parse(message),
+            trailing: Some(data[end..].to_vec()),
+        })
     }
 
-    fn as_bytes(&self) -> Vec<u8> {
-        let mut buf: Vec<u8> = Vec::new();
-        buf.write_u8(0x06).unwrap();
-        buf.write_u8(0).unwrap();
-        buf.write_u16::<LittleEndian>(4 + self.raw.len() as u16).unwrap();
-        buf.extend(&self.raw);
+    fn encode(&self, wide: bool) -> Vec<u8> {
+        let mut text = String::new();
+        if let Some(ref sender) = self.sender {
+            text.extend(sender.language.map(|l| l.marker()));
+            text.push_str(&sender.name);
+            text.push('\t');
+        }
+        text.extend(self.language.map(|l| l.marker()));
+        for t in self.text.iter() {
+            match t {
+                ChatText::Text(plain) => text.push_str(plain),
+                ChatText::Color(color) => {
+                    text.push_str("\tC");
+                    text.push(*color);
+                },
+            }
+        }
+
+        let mut buf = Vec::new();
+        buf.write_u32::<LittleEndian>(self.unused).unwrap();
+        buf.write_u32::<LittleEndian>(self.guildcard).unwrap();
+        if wide {
+            buf.extend(text.encode_utf16().flat_map(|u| u.to_le_bytes().to_vec()));
+        }
+        else {
+            // anything past latin-1 could only have come from us building the message
+            buf.extend(text.chars().map(|c| if (c as u32) < 0x100 { c as u8 } else { b'?' }));
+        }
+        match self.trailing {
+            Some(ref trailing) => buf.extend(trailing.iter()),
+            None => buf.extend(vec![0; if wide { 2 } else { 1 }]),
+        }
         buf
     }
 }
 
+impl PacketData for ChatMessage {
+    fn parse(cmd: u16, flag: u32, data: &Vec<u8>) -> Result<ChatMessage, ParseError> {
+        ChatMessage::decode(cmd, flag, data, false)
+    }
+
+    fn cmd(&self) -> u16 {
+        self.cmd
+    }
+
+    fn flag(&self) -> u32 {
+        self.flag
+    }
+
+    fn as_bytes(&self) -> Vec<u8> {
+        self.encode(false)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum Packet {
     Redirect(Redirect),
     EncryptionKeys(EncryptionKeys),
+    BBEncryptionKeys(BBEncryptionKeys),
     AllowDenyAccess(AllowDenyAccess),
 
     GameCommand(GameCommand),
     ChatMessage(ChatMessage),
     //ItemDrop(ItemDrop),
 
+    LobbyJoin(JoinLobby),
+    LobbyArrive(JoinLobby),
+    LobbyLeave(PlayerLeave),
+    GameJoin(JoinGame),
+    GameArrive(JoinLobby),
+    GameLeave(PlayerLeave),
+
     PlayerInventory(RawData),
     PlayerInformation(RawData),
     RawData(RawData)
@@ -223,29 +723,324 @@ pub enum Packet {
 
 
 impl Packet {
-    pub fn parse(cmd: u8, flag: u8, _len: u16, data: &Vec<u8>) -> Packet {
-        match cmd {
-            0x06 => Packet::ChatMessage(ChatMessage::parse(cmd, flag, data)),
-            0x60 => Packet::GameCommand(GameCommand::parse(cmd, flag, data)),
-            0x19 => Packet::Redirect(Redirect::parse(cmd, flag, data)),
-            0x17 | 0x02 => Packet::EncryptionKeys(EncryptionKeys::parse(cmd, flag, data)),
-            0x9A => Packet::AllowDenyAccess(AllowDenyAccess::parse(cmd, flag, data)),
-            0x61 => Packet::PlayerInventory(RawData::parse(cmd, flag, data)),
-            0x9E => Packet::PlayerInformation(RawData::parse(cmd, flag, data)),
-            _ => Packet::RawData(RawData::parse(cmd, flag, data))
-        }
+    // anything that doesn't parse as what its cmd says it is gets passed along as RawData
+    pub fn parse(version: ClientVersion, cmd: u16, flag: u32, data: &Vec<u8>) -> Packet {
+        let pkt = match cmd {
+            0x03 if version == ClientVersion::BlueBurst => BBEncryptionKeys::parse(cmd, flag, data).map(Packet::BBEncryptionKeys),
+            0x06 if version.narrow_text() => ChatMessage::parse(cmd, flag, data).map(Packet::ChatMessage),
+            0x06 => ChatMessage::parse_wide(cmd, flag, data).map(Packet::ChatMessage),
+            0x60 | 0x62 | 0x6C | 0x6D => GameCommand::parse_for(version, cmd, flag, data).map(Packet::GameCommand),
+            0x19 => Redirect::parse(cmd, flag, data).map(Packet::Redirect),
+            0x17 | 0x02 => EncryptionKeys::parse(cmd, flag, data).map(Packet::EncryptionKeys),
+            0x9A => AllowDenyAccess::parse(cmd, flag, data).map(Packet::AllowDenyAccess),
+            0x64 if version == ClientVersion::Gamecube => JoinGame::parse(cmd, flag, data).map(Packet::GameJoin),
+            0x65 if version.narrow_text() => JoinLobby::parse(cmd, flag, data).map(Packet::GameArrive),
+            0x66 => PlayerLeave::parse(cmd, flag, data).map(Packet::GameLeave),
+            0x67 if version.narrow_text() => JoinLobby::parse(cmd, flag, data).map(Packet::LobbyJoin),
+            0x68 if version.narrow_text() => JoinLobby::parse(cmd, flag, data).map(Packet::LobbyArrive),
+            0x69 => PlayerLeave::parse(cmd, flag, data).map(Packet::LobbyLeave),
+            0x61 => RawData::parse(cmd, flag, data).map(Packet::PlayerInventory),
+            0x9E => RawData::parse(cmd, flag, data).map(Packet::PlayerInformation),
+            _ => RawData::parse(cmd, flag, data).map(Packet::RawData),
+        };
+
+        pkt.unwrap_or_else(|err| {
+            warn!("could not parse packet {:02X}: {}", cmd, err);
+            Packet::RawData(RawData {
+                cmd: cmd,
+                flag: flag,
+                data: data.clone(),
+            })
+        })
+    }
+
+    // (cmd, name, layout) of every packet with known fields, the game commands' subcommands are in GameCommandAction::layouts
+    pub fn layouts() -> Vec<(u16, &'static str, &'static [Field])> {
+        vec![
+            (0x02, "EncryptionKeys", EncryptionKeys::LAYOUT),
+            (0x06, "ChatMessage", ChatMessage::LAYOUT),
+            (0x17, "EncryptionKeys", EncryptionKeys::LAYOUT),
+            (0x19, "Redirect", Redirect::LAYOUT),
+            (0x60, "GameCommand", GameCommand::LAYOUT),
+            (0x61, "PlayerInventory", RawData::LAYOUT),
+            (0x62, "GameCommandTo", GameCommand::LAYOUT),
+            (0x64, "GameJoin", JoinGame::LAYOUT),
+            (0x65, "GameArrive", JoinLobby::LAYOUT),
+            (0x66, "GameLeave", PlayerLeave::LAYOUT),
+            (0x67, "LobbyJoin", JoinLobby::LAYOUT),
+            (0x68, "LobbyArrive", JoinLobby::LAYOUT),
+            (0x69, "LobbyLeave", PlayerLeave::LAYOUT),
+            (0x6C, "GameCommandLarge", GameCommand::EXTENDED_LAYOUT),
+            (0x6D, "GameCommandLargeTo", GameCommand::EXTENDED_LAYOUT),
+            (0x9A, "AllowDenyAccess", AllowDenyAccess::LAYOUT),
+            (0x9E, "PlayerInformation", RawData::LAYOUT),
+        ]
     }
 
-    pub fn as_bytes(&self) -> Vec<u8> {
+    fn data(&self) -> &dyn PacketData {
         match self {
-            Packet::Redirect(pkt) => pkt.as_bytes(),
-            Packet::EncryptionKeys(pkt) => pkt.as_bytes(),
-            Packet::AllowDenyAccess(pkt) => pkt.as_bytes(),
-            Packet::GameCommand(pkt) => pkt.as_bytes(),
-            Packet::ChatMessage(pkt) => pkt.as_bytes(),
-            Packet::PlayerInventory(pkt) => pkt.as_bytes(),
-            Packet::PlayerInformation(pkt) => pkt.as_bytes(),
-            Packet::RawData(pkt) => pkt.as_bytes(),
+            Packet::Redirect(pkt) => pkt,
+            Packet::EncryptionKeys(pkt) => pkt,
+            Packet::BBEncryptionKeys(pkt) => pkt,
+            Packet::AllowDenyAccess(pkt) => pkt,
+            Packet::GameCommand(pkt) => pkt,
+            Packet::ChatMessage(pkt) => pkt,
+            Packet::LobbyJoin(pkt) => pkt,
+            Packet::LobbyArrive(pkt) => pkt,
+            Packet::LobbyLeave(pkt) => pkt,
+            Packet::GameJoin(pkt) => pkt,
+            Packet::GameArrive(pkt) => pkt,
+            Packet::GameLeave(pkt) => pkt,
+            Packet::PlayerInventory(pkt) => pkt,
+            Packet::PlayerInformation(pkt) => pkt,
+            Packet::RawData(pkt) => pkt,
         }
     }
+
+    pub fn cmd(&self) -> u16 {
+        self.data().cmd()
+    }
+
+    pub fn as_bytes(&self, version: ClientVersion) -> Vec<u8> {
+        let data = self.data();
+        let body = match self {
+            Packet::ChatMessage(chat) if !version.narrow_text() => chat.as_wide_bytes(),
+            _ => data.as_bytes(),
+        };
+        version.frame(data.cmd(), data.flag(), &body)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_layouts() {
+        let pkt = Packet::AllowDenyAccess(AllowDenyAccess { allow: 1, trailing: Vec::new() });
+        assert_eq!(pkt.as_bytes(ClientVersion::Gamecube), vec![0x9A, 0x01, 0x04, 0x00]);
+        assert_eq!(pkt.as_bytes(ClientVersion::Dreamcast), vec![0x9A, 0x01, 0x04, 0x00]);
+        assert_eq!(pkt.as_bytes(ClientVersion::PC), vec![0x04, 0x00, 0x9A, 0x01]);
+        assert_eq!(pkt.as_bytes(ClientVersion::BlueBurst), vec![0x08, 0x00, 0x9A, 0x00, 0x01, 0x00, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn padding() {
+        let pkt = Packet::RawData(RawData { cmd: 0x1D, flag: 0, data: vec![1, 2, 3] });
+
+        // gc counts the padding in the length
+        let gc = pkt.as_bytes(ClientVersion::Gamecube);
+        assert_eq!(gc, vec![0x1D, 0x00, 0x08, 0x00, 1, 2, 3, 0]);
+        let header = ClientVersion::Gamecube.read_header(&gc).unwrap();
+        assert_eq!(ClientVersion::Gamecube.wire_len(&header), 8);
+
+        // bb does not, but still pads to 8
+        let bb = pkt.as_bytes(ClientVersion::BlueBurst);
+        assert_eq!(bb, vec![0x0B, 0x00, 0x1D, 0x00, 0x00, 0x00, 0x00, 0x00, 1, 2, 3, 0, 0, 0, 0, 0]);
+        let header = ClientVersion::BlueBurst.read_header(&bb).unwrap();
+        assert_eq!(header, PacketHeader { cmd: 0x1D, flag: 0, len: 0x0B });
+        assert_eq!(ClientVersion::BlueBurst.wire_len(&header), 16);
+    }
+
+    // every prefix shorter than `min_len` has to come back as RawData, anything longer as the real thing
+    fn check_truncated<F: Fn(&Packet) -> bool>(version: ClientVersion, cmd: u16, body: Vec<u8>, min_len: usize, is_typed: F) {
+        for len in 0..=body.len() {
+            let data = body[..len].to_vec();
+            let pkt = Packet::parse(version, cmd, 0, &data);
+            if len < min_len {
+                match pkt {
+                    Packet::RawData(ref raw) => {
+                        assert_eq!(raw.cmd, cmd);
+                        assert_eq!(raw.data, data);
+                    },
+                    _ => panic!("cmd {:02X} len {} parsed as {:?}", cmd, len, pkt),
+                }
+            }
+            else {
+                assert!(is_typed(&pkt), "cmd {:02X} len {} parsed as {:?}", cmd, len, pkt);
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_redirect() {
+        check_truncated(ClientVersion::Gamecube, 0x19, vec![10, 0, 0, 1, 0x8C, 0x23, 0, 0], 6,
+                        |pkt| matches!(pkt, Packet::Redirect(_)));
+    }
+
+    #[test]
+    fn truncated_encryption_keys() {
+        for cmd in [0x02, 0x17].iter() {
+            check_truncated(ClientVersion::Gamecube, *cmd, vec![0x41; 0x4C], 0x48,
+                            |pkt| matches!(pkt, Packet::EncryptionKeys(_)));
+        }
+    }
+
+    #[test]
+    fn truncated_bb_encryption_keys() {
+        check_truncated(ClientVersion::BlueBurst, 0x03, vec![0x41; 0xC8], 0xC0,
+                        |pkt| matches!(pkt, Packet::BBEncryptionKeys(_)));
+    }
+
+    #[test]
+    fn truncated_lobby() {
+        for cmd in [0x65, 0x67, 0x68].iter() {
+            check_truncated(ClientVersion::Gamecube, *cmd, vec![0; 0x10], 0x0C,
+                            |pkt| matches!(pkt, Packet::LobbyJoin(_) | Packet::LobbyArrive(_) | Packet::GameArrive(_)));
+        }
+        check_truncated(ClientVersion::Gamecube, 0x64, vec![0; 0x114], 0x10C,
+                        |pkt| matches!(pkt, Packet::GameJoin(_)));
+        for cmd in [0x66, 0x69].iter() {
+            check_truncated(ClientVersion::Gamecube, *cmd, vec![0; 8], 4,
+                            |pkt| matches!(pkt, Packet::LobbyLeave(_) | Packet::GameLeave(_)));
+        }
+    }
+
+    #[test]
+    fn truncated_game_command() {
+        check_truncated(ClientVersion::Gamecube, 0x60, vec![0x1F, 0x02, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00], 4,
+                        |pkt| matches!(pkt, Packet::GameCommand(_)));
+    }
+
+    #[test]
+    fn truncated_chat_message() {
+        check_truncated(ClientVersion::Gamecube, 0x06, b"\0\0\0\0\0\0\0\0Asphodel\t\tJ123\0".to_vec(), 8,
+                        |pkt| matches!(pkt, Packet::ChatMessage(_)));
+    }
+
+    #[test]
+    fn chat_message_sender() {
+        let data = b"\0\0\0\0\xEC\x36\x32\xA0Asphodel\t\tJ12345678\0\0".to_vec();
+        match Packet::parse(ClientVersion::Gamecube, 0x06, 0, &data) {
+            Packet::ChatMessage(chat) => {
+                assert_eq!(chat.guildcard, 0xA03236EC);
+                assert_eq!(chat.name(), "Asphodel");
+                assert_eq!(chat.language, Some(Language::Japanese));
+                assert_eq!(chat.message(), "12345678");
+            },
+            pkt => panic!("parsed as {:?}", pkt),
+        }
+
+        let data = b"\0\0\0\0\0\0\0\0\tE/who\0\0\0".to_vec();
+        match Packet::parse(ClientVersion::Gamecube, 0x06, 0, &data) {
+            Packet::ChatMessage(chat) => {
+                assert!(chat.sender.is_none());
+                assert_eq!(chat.message(), "/who");
+            },
+            pkt => panic!("parsed as {:?}", pkt),
+        }
+    }
+
+    #[test]
+    fn chat_message_colors() {
+        let data = b"\0\0\0\0\x39\x30\0\0\tEKireek\t\tEwatch \tC6out\tC7 for that\0".to_vec();
+        let chat = match Packet::parse(ClientVersion::Gamecube, 0x06, 0, &data) {
+            Packet::ChatMessage(chat) => chat,
+            pkt => panic!("parsed as {:?}", pkt),
+        };
+        assert_eq!(chat.sender, Some(ChatSender { language: Some(Language::English), name: "Kireek".to_string() }));
+        assert_eq!(chat.text, vec![
+            ChatText::Text("watch ".to_string()),
+            ChatText::Color('6'),
+            ChatText::Text("out".to_string()),
+            ChatText::Color('7'),
+            ChatText::Text(" for that".to_string()),
+        ]);
+        assert_eq!(chat.message(), "watch out for that");
+        assert_eq!(chat.as_bytes(), data);
+    }
+
+    #[test]
+    fn build_chat_message() {
+        let to_player = ChatMessage::to_player("darkbridge", "\tC4hi");
+        assert_eq!(to_player.as_bytes(), b"\0\0\0\0\0\0\0\0\tEdarkbridge\t\tE\tC4hi\0".to_vec());
+        let from_player = ChatMessage::from_player("hello");
+        assert_eq!(from_player.as_bytes(), b"\0\0\0\0\0\0\0\0\tEhello\0".to_vec());
+
+        // and they read back as what went in
+        match Packet::parse(ClientVersion::Gamecube, 0x06, 0, &to_player.as_bytes()) {
+            Packet::ChatMessage(chat) => {
+                assert_eq!(chat.name(), "darkbridge");
+                assert_eq!(chat.text, vec![ChatText::Color('4'), ChatText::Text("hi".to_string())]);
+            },
+            pkt => panic!("parsed as {:?}", pkt),
+        }
+    }
+
+    #[test]
+    fn wide_chat_message() {
+        let mut data = vec![0, 0, 0, 0, 0x39, 0x30, 0, 0];
+        data.extend("\tEKireek\t\tE/who\u{0}".encode_utf16().flat_map(|u| u.to_le_bytes().to_vec()));
+        data.extend([0, 0].iter());
+        let chat = match Packet::parse(ClientVersion::BlueBurst, 0x06, 0, &data) {
+            Packet::ChatMessage(chat) => chat,
+            pkt => panic!("parsed as {:?}", pkt),
+        };
+        assert_eq!((chat.name(), chat.message()), ("Kireek", "/who".to_string()));
+        assert_eq!(chat.as_wide_bytes(), data);
+
+        // what we make up goes out as wide as the session's text
+        let reply = Packet::ChatMessage(ChatMessage::to_player("darkbridge", "hi"));
+        let wire = reply.as_bytes(ClientVersion::PC);
+        let header = ClientVersion::PC.read_header(&wire).unwrap();
+        match Packet::parse(ClientVersion::PC, 0x06, 0, &wire[ClientVersion::PC.header_size()..header.len as usize].to_vec()) {
+            Packet::ChatMessage(chat) => assert_eq!((chat.name(), chat.message()), ("darkbridge", "hi".to_string())),
+            pkt => panic!("parsed as {:?}", pkt),
+        }
+
+        // an unpaired surrogate goes through untouched
+        assert!(matches!(Packet::parse(ClientVersion::BlueBurst, 0x06, 0, &vec![0, 0, 0, 0, 0, 0, 0, 0, 0x00, 0xD8, 0, 0]), Packet::RawData(_)));
+    }
+
+    // bodies of random length and content, subcommand byte picked so game commands hit the typed ones too
+    #[test]
+    fn round_trip() {
+        let mut state = 0x2545F491u32;
+        let mut rand = move || {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state
+        };
+
+        for version in [ClientVersion::Gamecube, ClientVersion::PC, ClientVersion::BlueBurst].iter() {
+            for cmd in [0x02, 0x03, 0x06, 0x17, 0x19, 0x1D, 0x60, 0x61, 0x62, 0x64, 0x65, 0x66, 0x67, 0x68, 0x69, 0x6C, 0x6D, 0x9A, 0x9E].iter() {
+                for _ in 0..200 {
+                    let flag = match version {
+                        ClientVersion::BlueBurst => rand(),
+                        _ => rand() & 0xFF,
+                    };
+                    let mut len = rand() as usize % 0x180;
+                    if *version != ClientVersion::BlueBurst {
+                        len &= !3;
+                    }
+                    let mut body = (0..len).map(|_| rand() as u8).collect::<Vec<_>>();
+                    if [0x60, 0x62, 0x6C, 0x6D].contains(cmd) && len > 0 {
+                        let known = GameCommandAction::layouts().iter().map(|(gcmd, _, _, _)| *gcmd).collect::<Vec<_>>();
+                        body[0] = if rand() % 4 == 0 { rand() as u8 } else { known[rand() as usize % known.len()] };
+                    }
+
+                    let wire = version.frame(*cmd, flag, &body);
+                    let header = version.read_header(&wire).unwrap();
+                    let data = wire[version.header_size()..header.len as usize].to_vec();
+                    let pkt = Packet::parse(*version, header.cmd, header.flag, &data);
+                    assert_eq!(pkt.cmd(), *cmd);
+                    assert_eq!(pkt.data().flag(), flag);
+                    assert_eq!(pkt.as_bytes(*version), wire, "{:?}", pkt);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_never_fails() {
+        // these have no fixed fields so any length is fine
+        check_truncated(ClientVersion::Gamecube, 0x9A, vec![0; 4], 0,
+                        |pkt| matches!(pkt, Packet::AllowDenyAccess(_)));
+        check_truncated(ClientVersion::Gamecube, 0x61, vec![0; 8], 0,
+                        |pkt| matches!(pkt, Packet::PlayerInventory(_)));
+        check_truncated(ClientVersion::Gamecube, 0x9E, vec![0; 8], 0,
+                        |pkt| matches!(pkt, Packet::PlayerInformation(_)));
+    }
 }
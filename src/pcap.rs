@@ -0,0 +1,270 @@
+use std::fs::File;
+use std::io::{Write, BufWriter};
+use std::path::Path;
+use byteorder::{WriteBytesExt, LittleEndian, BigEndian};
+
+use crate::capture::{Tap, Flow, now};
+
+// pcapng blocks, all little endian
+const BLOCK_SHB: u32 = 0x0A0D0D0A;
+const BLOCK_IDB: u32 = 0x00000001;
+const BLOCK_EPB: u32 = 0x00000006;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B3C4D;
+// bare ipv4/ipv6, no link layer header
+const LINKTYPE_RAW: u16 = 101;
+const OPT_ENDOFOPT: u16 = 0;
+const OPT_EPB_FLAGS: u16 = 2;
+const EPB_INBOUND: u32 = 1;
+const EPB_OUTBOUND: u32 = 2;
+
+const IP_HEADER_LEN: usize = 20;
+const TCP_HEADER_LEN: usize = 20;
+const MAX_SEGMENT: usize = 0xFFFF - IP_HEADER_LEN - TCP_HEADER_LEN;
+
+// made up addresses so the two legs of the proxy show up as two tcp conversations,
+// with the pso port on the server end of both so dissectors pick them up
+const GAMECUBE_ADDR: ([u8; 4], u16) = ([10, 0, 0, 1], 49152);
+const PROXY_LISTEN_ADDR: ([u8; 4], u16) = ([10, 0, 0, 2], 9100);
+const PROXY_CONNECT_ADDR: ([u8; 4], u16) = ([10, 0, 0, 2], 49153);
+const SERVER_ADDR: ([u8; 4], u16) = ([10, 0, 0, 3], 9100);
+
+fn flow_index(flow: Flow) -> usize {
+    match flow {
+        Flow::FromGamecube => 0,
+        Flow::ToGamecube => 1,
+        Flow::ToServer => 2,
+        Flow::FromServer => 3,
+    }
+}
+
+// the flow going the other way on the same leg, whose seq is this one's ack
+fn reverse_flow(flow: Flow) -> Flow {
+    match flow {
+        Flow::FromGamecube => Flow::ToGamecube,
+        Flow::ToGamecube => Flow::FromGamecube,
+        Flow::ToServer => Flow::FromServer,
+        Flow::FromServer => Flow::ToServer,
+    }
+}
+
+fn endpoints(flow: Flow) -> (([u8; 4], u16), ([u8; 4], u16)) {
+    match flow {
+        Flow::FromGamecube => (GAMECUBE_ADDR, PROXY_LISTEN_ADDR),
+        Flow::ToGamecube => (PROXY_LISTEN_ADDR, GAMECUBE_ADDR),
+        Flow::ToServer => (PROXY_CONNECT_ADDR, SERVER_ADDR),
+        Flow::FromServer => (SERVER_ADDR, PROXY_CONNECT_ADDR),
+    }
+}
+
+fn checksum(chunks: &[&[u8]]) -> u16 {
+    let mut sum = 0u32;
+    for chunk in chunks {
+        for pair in chunk.chunks(2) {
+            let word = ((pair[0] as u32) << 8) | *pair.get(1).unwrap_or(&0) as u32;
+            sum += word;
+        }
+    }
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+fn ip_header(src: [u8; 4], dst: [u8; 4], id: u16, payload_len: usize) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.write_u8(0x45).unwrap();
+    buf.write_u8(0).unwrap();
+    buf.write_u16::<BigEndian>((IP_HEADER_LEN + payload_len) as u16).unwrap();
+    buf.write_u16::<BigEndian>(id).unwrap();
+    // don't fragment
+    buf.write_u16::<BigEndian>(0x4000).unwrap();
+    buf.write_u8(64).unwrap();
+    // tcp
+    buf.write_u8(6).unwrap();
+    buf.write_u16::<BigEndian>(0).unwrap();
+    buf.extend_from_slice(&src);
+    buf.extend_from_slice(&dst);
+    let sum = checksum(&[&buf]);
+    buf[10..12].copy_from_slice(&sum.to_be_bytes());
+    buf
+}
+
+fn tcp_header(src: ([u8; 4], u16), dst: ([u8; 4], u16), seq: u32, ack: u32, payload: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.write_u16::<BigEndian>(src.1).unwrap();
+    buf.write_u16::<BigEndian>(dst.1).unwrap();
+    buf.write_u32::<BigEndian>(seq).unwrap();
+    buf.write_u32::<BigEndian>(ack).unwrap();
+    buf.write_u8((TCP_HEADER_LEN as u8 / 4) << 4).unwrap();
+    // psh ack
+    buf.write_u8(0x18).unwrap();
+    buf.write_u16::<BigEndian>(0xFFFF).unwrap();
+    buf.write_u16::<BigEndian>(0).unwrap();
+    buf.write_u16::<BigEndian>(0).unwrap();
+
+    let mut pseudo = Vec::new();
+    pseudo.extend_from_slice(&src.0);
+    pseudo.extend_from_slice(&dst.0);
+    pseudo.write_u16::<BigEndian>(6).unwrap();
+    pseudo.write_u16::<BigEndian>((TCP_HEADER_LEN + payload.len()) as u16).unwrap();
+    let sum = checksum(&[&pseudo, &buf, payload]);
+    buf[16..18].copy_from_slice(&sum.to_be_bytes());
+    buf
+}
+
+// writes the decrypted stream as pcapng, each pso packet wrapped in a fake tcp segment
+pub struct PcapWriter {
+    writer: Box<dyn Write + Send>,
+    seq: [u32; 4],
+    ip_id: u16,
+}
+
+impl PcapWriter {
+    pub fn create<P: AsRef<Path>>(path: P) -> std::io::Result<PcapWriter> {
+        PcapWriter::new(BufWriter::new(File::create(path)?))
+    }
+
+    pub fn new<W: Write + Send + 'static>(mut writer: W) -> std::io::Result<PcapWriter> {
+        // section header, no options, section length unknown
+        writer.write_u32::<LittleEndian>(BLOCK_SHB)?;
+        writer.write_u32::<LittleEndian>(28)?;
+        writer.write_u32::<LittleEndian>(BYTE_ORDER_MAGIC)?;
+        writer.write_u16::<LittleEndian>(1)?;
+        writer.write_u16::<LittleEndian>(0)?;
+        writer.write_i64::<LittleEndian>(-1)?;
+        writer.write_u32::<LittleEndian>(28)?;
+
+        // one interface, timestamps default to usecs
+        writer.write_u32::<LittleEndian>(BLOCK_IDB)?;
+        writer.write_u32::<LittleEndian>(20)?;
+        writer.write_u16::<LittleEndian>(LINKTYPE_RAW)?;
+        writer.write_u16::<LittleEndian>(0)?;
+        writer.write_u32::<LittleEndian>(0)?;
+        writer.write_u32::<LittleEndian>(20)?;
+
+        Ok(PcapWriter {
+            writer: Box::new(writer),
+            seq: [1; 4],
+            ip_id: 0,
+        })
+    }
+
+    fn write_block(&mut self, timestamp: u64, flags: u32, frame: &[u8]) -> std::io::Result<()> {
+        let padding = (4 - frame.len() % 4) % 4;
+        // type, len, interface, ts high, ts low, caplen, origlen, frame, flags option, end of options, len
+        let block_len = 28 + frame.len() + padding + 8 + 4 + 4;
+
+        self.writer.write_u32::<LittleEndian>(BLOCK_EPB)?;
+        self.writer.write_u32::<LittleEndian>(block_len as u32)?;
+        self.writer.write_u32::<LittleEndian>(0)?;
+        self.writer.write_u32::<LittleEndian>((timestamp >> 32) as u32)?;
+        self.writer.write_u32::<LittleEndian>(timestamp as u32)?;
+        self.writer.write_u32::<LittleEndian>(frame.len() as u32)?;
+        self.writer.write_u32::<LittleEndian>(frame.len() as u32)?;
+        self.writer.write_all(frame)?;
+        self.writer.write_all(&[0u8; 3][..padding])?;
+        self.writer.write_u16::<LittleEndian>(OPT_EPB_FLAGS)?;
+        self.writer.write_u16::<LittleEndian>(4)?;
+        self.writer.write_u32::<LittleEndian>(flags)?;
+        self.writer.write_u16::<LittleEndian>(OPT_ENDOFOPT)?;
+        self.writer.write_u16::<LittleEndian>(0)?;
+        self.writer.write_u32::<LittleEndian>(block_len as u32)
+    }
+
+    pub fn write_packet(&mut self, timestamp: u64, flow: Flow, data: &[u8]) -> std::io::Result<()> {
+        let (src, dst) = endpoints(flow);
+        let flags = match flow {
+            Flow::FromGamecube | Flow::FromServer => EPB_INBOUND,
+            Flow::ToGamecube | Flow::ToServer => EPB_OUTBOUND,
+        };
+
+        for segment in data.chunks(MAX_SEGMENT) {
+            let seq = self.seq[flow_index(flow)];
+            let ack = self.seq[flow_index(reverse_flow(flow))];
+            self.seq[flow_index(flow)] = seq.wrapping_add(segment.len() as u32);
+            self.ip_id = self.ip_id.wrapping_add(1);
+
+            let mut frame = ip_header(src.0, dst.0, self.ip_id, TCP_HEADER_LEN + segment.len());
+            frame.extend(tcp_header(src, dst, seq, ack, segment));
+            frame.extend_from_slice(segment);
+            self.write_block(timestamp, flags, &frame)?;
+        }
+        self.writer.flush()
+    }
+}
+
+impl Tap for PcapWriter {
+    fn packet(&mut self, flow: Flow, data: &[u8]) -> std::io::Result<()> {
+        self.write_packet(now(), flow, data)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use crate::capture::tests::SharedBuf;
+
+    fn u32_at(buf: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes([buf[offset], buf[offset + 1], buf[offset + 2], buf[offset + 3]])
+    }
+
+    // (timestamp, flags, frame) for every enhanced packet block
+    fn packets(buf: &[u8]) -> Vec<(u64, u32, Vec<u8>)> {
+        let mut offset = 0;
+        let mut pkts = Vec::new();
+        while offset < buf.len() {
+            let block_type = u32_at(buf, offset);
+            let block_len = u32_at(buf, offset + 4) as usize;
+            assert_eq!(u32_at(buf, offset + block_len - 4) as usize, block_len);
+            if block_type == BLOCK_EPB {
+                let timestamp = ((u32_at(buf, offset + 12) as u64) << 32) | u32_at(buf, offset + 16) as u64;
+                let caplen = u32_at(buf, offset + 20) as usize;
+                let frame = buf[offset + 28..offset + 28 + caplen].to_vec();
+                let options = offset + 28 + (caplen + 3) / 4 * 4;
+                assert_eq!(u32_at(buf, options), 0x00040002);
+                pkts.push((timestamp, u32_at(buf, options + 4), frame));
+            }
+            offset += block_len;
+        }
+        pkts
+    }
+
+    #[test]
+    fn blocks_and_headers() {
+        let buf = SharedBuf(Arc::new(Mutex::new(Vec::new())));
+        let mut pcap = PcapWriter::new(buf.clone()).unwrap();
+        pcap.write_packet(0x0000000100000002, Flow::FromGamecube, &[0x1D, 0x00, 0x08, 0x00, 1, 2, 3]).unwrap();
+        pcap.write_packet(0x0000000100000003, Flow::ToServer, &[0x1D, 0x00, 0x04, 0x00]).unwrap();
+        pcap.write_packet(0x0000000100000004, Flow::FromGamecube, &[0x1D, 0x00, 0x04, 0x00]).unwrap();
+        pcap.write_packet(0x0000000100000005, Flow::ToGamecube, &[0x1D, 0x00, 0x04, 0x00]).unwrap();
+
+        let data = buf.0.lock().unwrap().clone();
+        assert_eq!(u32_at(&data, 0), BLOCK_SHB);
+        assert_eq!(u32_at(&data, 8), BYTE_ORDER_MAGIC);
+        assert_eq!(u32_at(&data, 28), BLOCK_IDB);
+        assert_eq!(u16::from_le_bytes([data[36], data[37]]), LINKTYPE_RAW);
+
+        let pkts = packets(&data);
+        assert_eq!(pkts.len(), 4);
+        let (timestamp, flags, ref frame) = pkts[0];
+        assert_eq!(timestamp, 0x0000000100000002);
+        assert_eq!(flags, EPB_INBOUND);
+        assert_eq!(frame.len(), IP_HEADER_LEN + TCP_HEADER_LEN + 7);
+        assert_eq!(&frame[12..16], &GAMECUBE_ADDR.0);
+        assert_eq!(&frame[16..20], &PROXY_LISTEN_ADDR.0);
+        assert_eq!(checksum(&[&frame[..IP_HEADER_LEN]]), 0);
+        assert_eq!(&frame[IP_HEADER_LEN + TCP_HEADER_LEN..], &[0x1D, 0x00, 0x08, 0x00, 1, 2, 3]);
+
+        // the other leg is its own conversation, starting from its own seq
+        let seq = |frame: &Vec<u8>| u32::from_be_bytes([frame[24], frame[25], frame[26], frame[27]]);
+        let ack = |frame: &Vec<u8>| u32::from_be_bytes([frame[28], frame[29], frame[30], frame[31]]);
+        assert_eq!(pkts[1].1, EPB_OUTBOUND);
+        assert_eq!(&pkts[1].2[16..20], &SERVER_ADDR.0);
+        assert_eq!(seq(&pkts[1].2), 1);
+        assert_eq!(seq(&pkts[2].2), 8);
+        assert_eq!(seq(&pkts[3].2), 1);
+        assert_eq!(ack(&pkts[3].2), 12);
+    }
+}
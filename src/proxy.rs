@@ -1,39 +1,67 @@
+use std::convert::TryFrom;
 use std::fs::File;
 use std::fs::OpenOptions;
 use std::os::unix::fs::OpenOptionsExt;
 use std::os::unix::io::{RawFd, AsRawFd};
 use std::net;
-use std::net::{SocketAddr, Ipv4Addr};
+use std::net::{Ipv4Addr, IpAddr};
 use mio::Poll;
 use mio::*;
 use mio::net::{TcpStream, TcpListener};
 use mio::unix::SourceFd;
-use std::io::{Read, Write, Cursor, BufReader, BufRead};
-use byteorder::{ReadBytesExt, WriteBytesExt, LittleEndian};
+use std::io::{BufReader, BufRead};
 use nix::unistd;
 use nix::sys::stat;
 
 use crate::filters;
 use crate::filters::TargettedPacket;
-use crate::packet::Packet;
-use crate::cipher::Cipher;
-use crate::commands::{Command, CommandRunner};
-
-const PSOPORT: u16 = 9100;
-
-// unseen
-const TARGET_SERVER: Ipv4Addr = Ipv4Addr::new(47, 87, 165, 199);
-// scht
-//const TARGET_SERVER: Ipv4Addr = Ipv4Addr::new(149, 56, 167, 128);
-// elsewhere
-//const TARGET_SERVER: Ipv4Addr = Ipv4Addr::new(45, 33, 31, 247);
-
+use crate::packet::{Packet, ClientVersion};
+use crate::cipher::{Cipher, BBCipher, BBKeyFile, PacketCipher, Direction, BB_SEED_LEN};
+use crate::commands::CommandRunner;
+use crate::config::Config;
+use crate::connection::{Connection, Fill, MAX_BUFFER};
+use crate::capture::{Recorder, Tap, Flow};
+use crate::pcap::PcapWriter;
+use crate::chatlog::ChatLog;
+use crate::roster::Roster;
+use crate::gameitems::GameItems;
+use crate::logging::{Level, hexdump};
 
 pub const GAMECUBE: Token = Token(0);
 pub const SERVER: Token = Token(1);
 pub const LISTENER: Token = Token(2);
 pub const CMDPIPE: Token = Token(3);
 
+#[derive(Debug)]
+pub enum ProxyError {
+    Io(std::io::Error),
+    Disconnected(Token),
+    BadHeader(u16),
+    NoRedirectIp,
+    NoBBKeyFile,
+    // length of a seed that doesn't fit the session's cipher
+    BadSeed(usize),
+}
+
+impl std::fmt::Display for ProxyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ProxyError::Io(err) => write!(f, "io error: {}", err),
+            ProxyError::Disconnected(token) => write!(f, "{} disconnected", if *token == GAMECUBE {"gamecube"} else {"server"}),
+            ProxyError::BadHeader(len) => write!(f, "bad header, packet length {:#x}", len),
+            ProxyError::NoRedirectIp => write!(f, "no address to redirect the gamecube to"),
+            ProxyError::NoBBKeyFile => write!(f, "blue burst needs a key file"),
+            ProxyError::BadSeed(len) => write!(f, "cipher seed is {} bytes", len),
+        }
+    }
+}
+
+impl From<std::io::Error> for ProxyError {
+    fn from(err: std::io::Error) -> ProxyError {
+        ProxyError::Io(err)
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct Position {
     pub x: f32,
@@ -41,8 +69,34 @@ pub struct Position {
     pub z: f32,
 }
 
+// where the player is, as far as the join packets have told us
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Location {
+    Unknown,
+    // lobby is 0 based, shown 1 based like the game does
+    Lobby { lobby: u8, block: u16 },
+    Game,
+}
+
+impl std::fmt::Display for Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Location::Unknown => write!(f, "-"),
+            Location::Lobby { lobby, block } => write!(f, "lobby {} block {}", *lobby as u32 + 1, block),
+            Location::Game => write!(f, "game"),
+        }
+    }
+}
+
+#[derive(Debug)]
 pub struct GameState {
+    // our client id, from the last lobby or game join. anything we make up is sent as this client
     pub self_client: u8,
+    pub location: Location,
+    // everyone in the lobby or game, us included
+    pub roster: Roster,
+    // what's on the floor and who's holding what, as far as the game commands have said
+    pub items: GameItems,
     pub floor: u32,
     pub position: Position,
     pub itemdrop_id: u32,
@@ -53,6 +107,9 @@ impl GameState {
     pub fn new() -> GameState {
         GameState {
             self_client: 0,
+            location: Location::Unknown,
+            roster: Roster::new(),
+            items: GameItems::new(),
             floor: 0,
             position: Position {x:0.0, y:0.0, z:0.0},
             itemdrop_id: 0x11223344,
@@ -67,227 +124,416 @@ impl GameState {
 }
 
 pub struct Proxy {
-    pub gamecube: TcpStream,
-    pub server: TcpStream,
+    pub session: u32,
+    pub gamecube: Connection,
+    pub server: Connection,
     pub listener: Option<TcpListener>,
     //cmd_pipe: File,
     pub poll: Poll,
+    pub config: Config,
 
     pub gamestate: GameState,
 
-    pub server2proxy: Option<Cipher>,
-    pub proxy2server: Option<Cipher>,
-    pub gamecube2proxy: Option<Cipher>,
-    pub proxy2gamecube: Option<Cipher>,
+    bb_keys: Option<BBKeyFile>,
+    pub server2proxy: Option<Box<dyn PacketCipher>>,
+    pub proxy2server: Option<Box<dyn PacketCipher>>,
+    pub gamecube2proxy: Option<Box<dyn PacketCipher>>,
+    pub proxy2gamecube: Option<Box<dyn PacketCipher>>,
+
+    // capture file, pcap export, whatever else wants to watch the decrypted traffic
+    pub taps: Vec<Box<dyn Tap>>,
+    pub chatlog: Option<ChatLog>,
 }
 
-pub fn print_buffer(pkt: &Vec<u8>) {
-    for (i, row) in pkt.chunks(16).enumerate() {
-        let mut hexbuf = Vec::new();
-        let mut asciibuf = Vec::new();
-        for item in row {
-            hexbuf.push(format!("{:02X}", item));
-            asciibuf.push(format!("{}", if *item > 0x20 && *item < 0x7E {*item as char} else {'.'}));
+// a tap that can't be written shouldn't take the session down with it
+fn tap_packet(session: u32, taps: &mut Vec<Box<dyn Tap>>, flow: Flow, data: &[u8]) {
+    taps.retain_mut(|tap| {
+        match tap.packet(flow, data) {
+            Ok(()) => true,
+            Err(err) => {
+                warn!("[{}] tap failed, dropping it: {}", session, err);
+                false
+            }
         }
-        println!("{:04X} | {:47} | {:16} |", i*16, hexbuf.join(" "), asciibuf.join(""));
-    }
+    });
 }
 
-fn get_packet(mut sock: &TcpStream, cipher: &mut Option<Cipher>) -> Option<Packet> {
-    let mut local_cipher = cipher.clone();
-
-    let mut header = vec![0u8; 4];
-    match sock.peek(&mut header) {
-        Ok(len) if len != 4 => return None,
-        Err(_) => return None,
-        _ => {}
-    }
-
-    if let Some(ref mut cipher) = local_cipher {
-        header = cipher.encrypt(&header.to_vec());
-    };
+fn would_block(err: &std::io::Error) -> bool {
+    err.kind() == std::io::ErrorKind::WouldBlock || err.kind() == std::io::ErrorKind::Interrupted
+}
 
-    let mut cur = Cursor::new(header.clone());
-    let cmd = cur.read_u8().unwrap();
-    let flag = cur.read_u8().unwrap();
-    let len = cur.read_u16::<LittleEndian>().unwrap();
+// both sockets get writable events too so queued packets drain as the socket frees up
+pub fn stream_interest() -> Interest {
+    Interest::READABLE | Interest::WRITABLE
+}
 
-    if len == 0 {
-        return None
+// Ok(None) means a full packet hasn't arrived yet
+fn get_packet(session: u32, version: ClientVersion, conn: &mut Connection, cipher: &mut Option<Box<dyn PacketCipher>>,
+              taps: &mut Vec<Box<dyn Tap>>, flow: Flow) -> Result<Option<Packet>, ProxyError> {
+    // the header is decrypted on its own so it has to be a whole cipher block
+    let header_len = cipher.as_ref().map(|c| c.block_size()).unwrap_or(0).max(version.header_size());
+    if conn.buffered().len() < header_len {
+        return Ok(None);
     }
 
-    let mut peek_buf = vec![0u8; len as usize];
-    let peek_len = match sock.peek(&mut peek_buf) {
-        Ok(l) => l,
-        Err(_e) => return None
+    // decrypting the header moves the cipher along, so undo that if the rest isn't here yet
+    let checkpoint = cipher.as_ref().map(|c| c.checkpoint());
+
+    let mut header_buf = conn.buffered()[..header_len].to_vec();
+    if let Some(ref mut cipher) = cipher {
+        cipher.apply(&mut header_buf);
     };
 
-    if len as usize != peek_len {
-        return None;
+    let header = version.read_header(&header_buf)?;
+    let wire_len = version.wire_len(&header);
+
+    if (header.len as usize) < version.header_size() || wire_len < header_len || conn.buffered().len() < wire_len {
+        if let (Some(cipher), Some(checkpoint)) = (cipher.as_mut(), checkpoint) {
+            cipher.rollback(checkpoint);
+        }
+        if (header.len as usize) < version.header_size() || wire_len < header_len {
+            return Err(ProxyError::BadHeader(header.len))
+        }
+        return Ok(None);
     }
 
-    let mut header_enc = vec![0u8; 4];
-    let mut data_buf = vec![0u8; len as usize - 4];
-    sock.read_exact(&mut header_enc).unwrap();
-    sock.read_exact(&mut data_buf).unwrap();
+    let mut data_buf = conn.consume(wire_len).split_off(header_len);
 
-    if let Some(ref mut cipher) = local_cipher {
-        data_buf = cipher.encrypt(&data_buf.to_vec());
+    if let Some(ref mut cipher) = cipher {
+        cipher.apply(&mut data_buf);
     };
 
-    *cipher = local_cipher;
+    // whatever of the body was decrypted along with the header, minus any padding
+    let mut data_buf = header_buf.split_off(version.header_size()).into_iter().chain(data_buf.into_iter()).collect::<Vec<_>>();
+    data_buf.truncate(header.len as usize - version.header_size());
 
-    let pkt = Packet::parse(cmd, flag, len, &data_buf);
-    print_buffer(&header.into_iter().chain(data_buf.into_iter()).collect());
+    let pkt = Packet::parse(version, header.cmd, header.flag, &data_buf);
+    let raw = header_buf.into_iter().chain(data_buf.into_iter()).collect::<Vec<_>>();
+    log_packet!(Level::Debug, header.cmd, "[{}] {:?} {:?}", session, flow, pkt);
+    log_packet!(Level::Trace, header.cmd, "[{}] {:?}\n{}", session, flow, hexdump(&raw));
+    tap_packet(session, taps, flow, &raw);
 
-    Some(pkt)
+    Ok(Some(pkt))
 }
 
-fn send_packet(sock: &mut TcpStream, pkt: &Packet, cipher: &mut Option<Cipher>) -> Result<(), std::io::Error> {
-    println!("sending to {:?}", sock);
-    let mut buf = pkt.as_bytes();
-    print_buffer(&buf);
+fn send_packet(session: u32, version: ClientVersion, conn: &mut Connection, pkt: &Packet, cipher: &mut Option<Box<dyn PacketCipher>>,
+               taps: &mut Vec<Box<dyn Tap>>, flow: Flow) -> Result<(), std::io::Error> {
+    let mut buf = pkt.as_bytes(version);
+    log_packet!(Level::Debug, pkt.cmd(), "[{}] {:?} {:?}", session, flow, pkt);
+    log_packet!(Level::Trace, pkt.cmd(), "[{}] {:?}\n{}", session, flow, hexdump(&buf));
+    tap_packet(session, taps, flow, &buf);
     if let Some(ref mut cipher) = cipher {
-        buf = cipher.encrypt(&buf);
+        cipher.apply(&mut buf);
     }
 
-    loop {
-        match sock.write_all(&buf) {
-            Err(err) => {
-                if err.kind() == std::io::ErrorKind::WouldBlock {
-                    continue
-                }
-                else {
-                    println!("erroring {:?}!", err);
-                    return Err(err)
-                }
-            },
-            _ => return Ok(()),
-        }
-    }
+    conn.queue(&buf)
 }
 
 impl Proxy {
-    pub fn new(sock: net::TcpStream) -> Proxy {
-        let server = TcpStream::connect(SocketAddr::from((TARGET_SERVER, PSOPORT))).unwrap();
+    pub fn new(session: u32, sock: net::TcpStream, config: Config) -> Result<Proxy, ProxyError> {
+        let server = TcpStream::connect(config.upstream.addr()?)?;
+        sock.set_nonblocking(true)?;
+
+        let bb_keys = match config.client {
+            ClientVersion::BlueBurst => Some(BBKeyFile::load(config.bb_key_file.as_ref().ok_or(ProxyError::NoBBKeyFile)?)?),
+            _ => None,
+        };
+
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let mut taps: Vec<Box<dyn Tap>> = Vec::new();
+        if let Some(ref dir) = config.capture_dir {
+            let path = dir.join(format!("darkbridge-{}-{}.cap", timestamp, session));
+            match std::fs::create_dir_all(dir).and_then(|_| Recorder::create(&path, session, config.client)) {
+                Ok(recorder) => {
+                    info!("[{}] recording to {:?}", session, path);
+                    taps.push(Box::new(recorder));
+                },
+                Err(err) => warn!("[{}] not recording, could not create {:?}: {}", session, path, err),
+            }
+        }
+        if let Some(ref dir) = config.pcap_dir {
+            let path = dir.join(format!("darkbridge-{}-{}.pcapng", timestamp, session));
+            match std::fs::create_dir_all(dir).and_then(|_| PcapWriter::create(&path)) {
+                Ok(writer) => {
+                    info!("[{}] exporting pcap to {:?}", session, path);
+                    taps.push(Box::new(writer));
+                },
+                Err(err) => warn!("[{}] not exporting pcap, could not create {:?}: {}", session, path, err),
+            }
+        }
+        let chatlog = config.chatlog_dir.as_ref().map(ChatLog::new);
 
-        Proxy {
-            gamecube: TcpStream::from_std(sock),
-            server: server,
+        Ok(Proxy {
+            session: session,
+            gamecube: Connection::new(TcpStream::from_std(sock)),
+            server: Connection::new(server),
             listener: None,
             //cmd_pipe: cmd_pipe,
-            poll: Poll::new().unwrap(),
+            poll: Poll::new()?,
+            config: config,
             gamestate: GameState::new(),
+            bb_keys: bb_keys,
             server2proxy: None,
             proxy2server: None,
             gamecube2proxy: None,
             proxy2gamecube: None,
+            taps: taps,
+            chatlog: chatlog,
+        })
+    }
+
+    // stand-in for running captured traffic through the filters, there are no sockets behind it
+    pub fn offline(session: u32, config: Config) -> Result<Proxy, ProxyError> {
+        Ok(Proxy {
+            session: session,
+            gamecube: Connection::detached(),
+            server: Connection::detached(),
+            listener: None,
+            poll: Poll::new()?,
+            config: config,
+            gamestate: GameState::new(),
+            bb_keys: None,
+            server2proxy: None,
+            proxy2server: None,
+            gamecube2proxy: None,
+            proxy2gamecube: None,
+            taps: Vec::new(),
+            chatlog: None,
+        })
+    }
+
+    // address the gamecube reached us on, unless the config says otherwise
+    pub fn redirect_ip(&self) -> Option<Ipv4Addr> {
+        self.config.redirect_ip.or_else(|| {
+            match self.gamecube.local_addr()?.ip() {
+                IpAddr::V4(ip) => Some(ip),
+                IpAddr::V6(ip) => ip.to_ipv4(),
+            }
+        })
+    }
+
+    // dc and pc would need the v2 cipher, the config turns them away
+    // a seed the wrong size for the session's cipher means the keys packet wasn't the
+    // one this version uses, better to drop the session than garble it
+    pub fn make_cipher(&self, seed: &[u8], direction: Direction) -> Result<Box<dyn PacketCipher>, ProxyError> {
+        match self.bb_keys {
+            Some(ref keys) => {
+                let seed = <&[u8; BB_SEED_LEN]>::try_from(seed).map_err(|_| ProxyError::BadSeed(seed.len()))?;
+                Ok(Box::new(BBCipher::new(keys, seed, direction)))
+            },
+            None => {
+                let seed = <[u8; 4]>::try_from(seed).map_err(|_| ProxyError::BadSeed(seed.len()))?;
+                Ok(Box::new(Cipher::new(u32::from_le_bytes(seed))))
+            },
         }
     }
 
-    fn filter_packet(&mut self, filters: &Vec<Box<filters::Filter>>, pkt: TargettedPacket) -> Vec<TargettedPacket> {
+    pub fn filter_packet(&mut self, filters: &Vec<Box<filters::Filter>>, pkt: TargettedPacket) -> Result<Vec<TargettedPacket>, ProxyError> {
         let mut pkts = vec![pkt];
         for filter in filters.iter() {
             let mut result_pkts = Vec::new();
             for p in pkts {
-                result_pkts.extend(filter(p, self));
+                result_pkts.extend(filter(p, self)?);
             }
             pkts = result_pkts;
         }
-        pkts
+        Ok(pkts)
     }
 
-    fn send_packets(&mut self, pkts: Vec<TargettedPacket>) -> Result<(), std::io::Error>{
+    fn tap_seeds(&mut self, server: &[u8], client: &[u8]) {
+        let session = self.session;
+        self.taps.retain_mut(|tap| {
+            match tap.seeds(server, client) {
+                Ok(()) => true,
+                Err(err) => {
+                    warn!("[{}] tap failed, dropping it: {}", session, err);
+                    false
+                }
+            }
+        });
+    }
+
+    fn send_packets(&mut self, pkts: Vec<TargettedPacket>) -> Result<(), ProxyError>{
         for pkt in pkts {
             match pkt {
                 TargettedPacket::Client(p) => {
-                    send_packet(&mut self.gamecube, &p, &mut self.proxy2gamecube)?;
+                    send_packet(self.session, self.config.client, &mut self.gamecube, &p, &mut self.proxy2gamecube,
+                                &mut self.taps, Flow::ToGamecube)?;
 
                     if let Packet::EncryptionKeys(ref keys) = p {
-                        println!("encryption keys! c: {:08X} s: {:08X}", keys.client_seed, keys.server_seed);
-                        self.server2proxy = Some(Cipher::new(keys.server_seed));
-                        self.proxy2server = Some(Cipher::new(keys.client_seed));
-                        self.proxy2gamecube = Some(Cipher::new(keys.server_seed));
-                        self.gamecube2proxy = Some(Cipher::new(keys.client_seed));
+                        debug!("[{}] encryption keys! c: {:08X} s: {:08X}", self.session, keys.client_seed, keys.server_seed);
+                        let server_seed = keys.server_seed.to_le_bytes();
+                        let client_seed = keys.client_seed.to_le_bytes();
+                        self.tap_seeds(&server_seed, &client_seed);
+                        self.server2proxy = Some(self.make_cipher(&server_seed, Direction::Decrypt)?);
+                        self.proxy2server = Some(self.make_cipher(&client_seed, Direction::Encrypt)?);
+                        self.proxy2gamecube = Some(self.make_cipher(&server_seed, Direction::Encrypt)?);
+                        self.gamecube2proxy = Some(self.make_cipher(&client_seed, Direction::Decrypt)?);
+                    }
+                    if let Packet::BBEncryptionKeys(ref keys) = p {
+                        debug!("[{}] bb encryption keys!", self.session);
+                        self.tap_seeds(&keys.server_key, &keys.client_key);
+                        self.server2proxy = Some(self.make_cipher(&keys.server_key, Direction::Decrypt)?);
+                        self.proxy2server = Some(self.make_cipher(&keys.client_key, Direction::Encrypt)?);
+                        self.proxy2gamecube = Some(self.make_cipher(&keys.server_key, Direction::Encrypt)?);
+                        self.gamecube2proxy = Some(self.make_cipher(&keys.client_key, Direction::Decrypt)?);
                     }
                 },
                 TargettedPacket::Server(p) => {
-                    send_packet(&mut self.server, &p, &mut self.proxy2server)?;
+                    send_packet(self.session, self.config.client, &mut self.server, &p, &mut self.proxy2server,
+                                &mut self.taps, Flow::ToServer)?;
                 }
             }
         }
         Ok(())
     }
 
-    pub fn run(&mut self) -> Result<(), std::io::Error> {
-        let _ = unistd::mkfifo("/tmp/darkbridge", stat::Mode::S_IRWXU);
+    fn connection(&mut self, token: Token) -> &mut Connection {
+        if token == GAMECUBE {
+            &mut self.gamecube
+        }
+        else {
+            &mut self.server
+        }
+    }
+
+    // stop taking in packets while either side has a large backlog of writes
+    fn backed_up(&self) -> bool {
+        self.gamecube.pending() > MAX_BUFFER || self.server.pending() > MAX_BUFFER
+    }
+
+    // reads whatever is waiting on one side and runs every complete packet through the filters
+    fn pump(&mut self, token: Token, filters: &Vec<Box<filters::Filter>>) -> Result<(), ProxyError> {
+        loop {
+            // mid-redirect, wait for the gamecube to reconnect before touching either socket
+            if self.listener.is_some() || self.backed_up() {
+                return Ok(());
+            }
+
+            let fill = self.connection(token).fill()?;
+
+            while !self.backed_up() && self.listener.is_none() {
+                let pkt = if token == GAMECUBE {
+                    get_packet(self.session, self.config.client, &mut self.gamecube, &mut self.gamecube2proxy,
+                               &mut self.taps, Flow::FromGamecube)?
+                }
+                else {
+                    get_packet(self.session, self.config.client, &mut self.server, &mut self.server2proxy,
+                               &mut self.taps, Flow::FromServer)?
+                };
+
+                let pkt = match pkt {
+                    Some(pkt) => pkt,
+                    None => break,
+                };
+
+                let filtered_pkts = if token == GAMECUBE {
+                    self.filter_packet(filters, TargettedPacket::Server(pkt))?
+                }
+                else {
+                    self.filter_packet(filters, TargettedPacket::Client(pkt))?
+                };
+                self.send_packets(filtered_pkts)?;
+            }
+
+            match fill {
+                Fill::Full => continue,
+                Fill::Drained => return Ok(()),
+                Fill::Closed if self.listener.is_some() => return Ok(()),
+                Fill::Closed => return Err(ProxyError::Disconnected(token)),
+            }
+        }
+    }
+
+    // runs until either side hangs up, a clean disconnect is not an error
+    pub fn run(&mut self) -> Result<(), ProxyError> {
+        // each session gets its own pipe so commands go to the right gamecube
+        let cmd_pipe_path = format!("/tmp/darkbridge.{}", self.session);
+        let _ = unistd::mkfifo(cmd_pipe_path.as_str(), stat::Mode::S_IRWXU);
+
+        let result = self.event_loop(&cmd_pipe_path);
+
+        let _ = self.gamecube.flush();
+        let _ = self.gamecube.shutdown();
+        let _ = self.server.shutdown();
+        let _ = std::fs::remove_file(&cmd_pipe_path);
+
+        match result {
+            Err(ProxyError::Disconnected(token)) => {
+                info!("[{}] {} disconnected, closing session", self.session, if token == GAMECUBE {"gamecube"} else {"server"});
+                Ok(())
+            },
+            result => result,
+        }
+    }
+
+    fn event_loop(&mut self, cmd_pipe_path: &str) -> Result<(), ProxyError> {
         let mut cmd_pipe = OpenOptions::new()
             .custom_flags(libc::O_NONBLOCK)
             .read(true)
-            .open("/tmp/darkbridge").unwrap();
+            .open(cmd_pipe_path)?;
+        info!("[{}] command pipe: {}", self.session, cmd_pipe_path);
 
-        self.poll.registry().register(&mut self.gamecube, GAMECUBE, Interest::READABLE).unwrap();
-        self.poll.registry().register(&mut self.server, SERVER, Interest::READABLE).unwrap();
-        self.poll.registry().register(&mut SourceFd(&cmd_pipe.as_raw_fd()), CMDPIPE, Interest::READABLE).unwrap();
+        self.gamecube.register(self.poll.registry(), GAMECUBE, stream_interest())?;
+        self.server.register(self.poll.registry(), SERVER, stream_interest())?;
+        self.poll.registry().register(&mut SourceFd(&cmd_pipe.as_raw_fd()), CMDPIPE, Interest::READABLE)?;
 
         let mut commandrunner = CommandRunner::new();
 
-        let mut filters: Vec<Box<filters::Filter>> = Vec::new();
-        filters.push(Box::new(filters::connection_redirect));
-        filters.push(Box::new(filters::save_position));
-        filters.push(Box::new(filters::chat_command));
-        filters.push(Box::new(filters::update_inventory));
+        let filters = filters::default_filters();
 
         let mut events = Events::with_capacity(64);
 
         loop {
-            self.poll.poll(&mut events, None).unwrap();
+            match self.poll.poll(&mut events, None) {
+                Err(ref err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+                result => result?,
+            }
 
             for event in events.iter() {
                 match event.token() {
-                    GAMECUBE => {
-                        println!("[GAMECUBE]");
-                        while let Some(pkt) = get_packet(&self.gamecube, &mut self.gamecube2proxy) {
-                            println!("gc! {:?}", pkt);
-                            let filtered_pkts = self.filter_packet(&filters, TargettedPacket::Server(pkt));
-                            self.send_packets(filtered_pkts)?;
-                        }
-                    },
-                    SERVER => {
-                        println!("[SERVER]");
-                        while let Some(pkt) = get_packet(&self.server, &mut self.server2proxy) {
-                            //println!("serv! {:?}", pkt);
-                            let filtered_pkts = self.filter_packet(&filters, TargettedPacket::Client(pkt));
-                            self.send_packets(filtered_pkts)?;
+                    GAMECUBE | SERVER => {
+                        trace!("[{}] [{}]", self.session, if event.token() == GAMECUBE {"GAMECUBE"} else {"SERVER"});
+                        if event.is_writable() {
+                            self.connection(event.token()).flush()?;
                         }
+                        // a flush on one side may be what the other side was waiting on
+                        self.pump(GAMECUBE, &filters)?;
+                        self.pump(SERVER, &filters)?;
                     },
                     LISTENER => {
-                        println!("[LISTENER]");
+                        trace!("[{}] [LISTENER]", self.session);
                         if let Some(ref mut listener) = self.listener {
-                            self.gamecube = listener.accept().unwrap().0;
-                            println!("accepted new gc: {:?}", self.gamecube);
+                            self.gamecube = match listener.accept() {
+                                Ok((sock, _)) => Connection::new(sock),
+                                Err(ref err) if would_block(err) => continue,
+                                Err(err) => return Err(err.into()),
+                            };
+                            info!("[{}] accepted new gc: {:?}", self.session, self.gamecube.sock());
                             self.server2proxy = None;
                             self.proxy2server = None;
                             self.gamecube2proxy = None;
                             self.proxy2gamecube = None;
-                            self.poll.registry().register(&mut self.gamecube, GAMECUBE, Interest::READABLE).unwrap();
-                            self.poll.registry().register(&mut self.server, SERVER, Interest::READABLE).unwrap();
+                            self.gamecube.register(self.poll.registry(), GAMECUBE, stream_interest())?;
+                            self.server.register(self.poll.registry(), SERVER, stream_interest())?;
                             //listener.shutdown();
                         }
                         self.listener = None;
                     },
                     CMDPIPE => {
-                        println!("[CMDPIPE]");
+                        trace!("[{}] [CMDPIPE]", self.session);
                         let cmdbuf = BufReader::new(&mut cmd_pipe);
                         for cmd in cmdbuf.lines() {
-                            let command = Command::parse(cmd.unwrap().to_ascii_lowercase());
-                            match command {
-                                Ok(c) => {
-                                    let pkts = commandrunner.run(c, self);
-                                    self.send_packets(pkts)?;
-                                },
-                                Err(err) => println!("!!! command error: {:?}", err),
-                            }
+                            let cmd = match cmd {
+                                Ok(cmd) => cmd,
+                                Err(ref err) if would_block(err) => break,
+                                Err(err) => return Err(err.into()),
+                            };
+                            let pkts = commandrunner.run_line(cmd, self);
+                            self.send_packets(pkts)?;
                         }
                     }
                     _ => unreachable!()
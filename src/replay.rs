@@ -0,0 +1,151 @@
+use std::io::Read;
+use std::path::Path;
+
+use crate::capture::{CaptureReader, CaptureError, Record, Flow};
+use crate::config::Config;
+use crate::filters::{self, Filter, TargettedPacket};
+use crate::packet::Packet;
+use crate::proxy::{Proxy, ProxyError};
+use crate::logging::hexdump;
+
+#[derive(Debug)]
+pub enum ReplayError {
+    Capture(CaptureError),
+    Proxy(ProxyError),
+    BadHeader(usize),
+}
+
+impl std::fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ReplayError::Capture(err) => write!(f, "bad capture: {}", err),
+            ReplayError::Proxy(err) => write!(f, "{}", err),
+            ReplayError::BadHeader(len) => write!(f, "bad header in a {} byte packet", len),
+        }
+    }
+}
+
+impl From<CaptureError> for ReplayError {
+    fn from(err: CaptureError) -> ReplayError {
+        ReplayError::Capture(err)
+    }
+}
+
+impl From<ProxyError> for ReplayError {
+    fn from(err: ProxyError) -> ReplayError {
+        ReplayError::Proxy(err)
+    }
+}
+
+// one captured packet and whatever the filters turned it into
+#[derive(Debug)]
+pub struct Replayed {
+    pub flow: Flow,
+    pub packet: Packet,
+    pub emitted: Vec<TargettedPacket>,
+}
+
+// only what came in off the wire is fed back through, what the proxy sent out
+// at the time is what the filters are expected to reproduce
+pub fn replay<R: Read>(proxy: &mut Proxy, capture: CaptureReader<R>, filters: &Vec<Box<Filter>>) -> Result<Vec<Replayed>, ReplayError> {
+    let version = capture.version;
+    let mut replayed = Vec::new();
+
+    for record in capture {
+        let (flow, data) = match record? {
+            Record::Packet { flow, data, .. } => (flow, data),
+            Record::Seeds { .. } => continue,
+        };
+
+        let pkt = match flow {
+            Flow::FromGamecube | Flow::FromServer => {
+                let header = version.read_header(&data).map_err(|_| ReplayError::BadHeader(data.len()))?;
+                Packet::parse(version, header.cmd, header.flag, &data[version.header_size()..].to_vec())
+            },
+            Flow::ToGamecube | Flow::ToServer => continue,
+        };
+
+        let targetted = match flow {
+            Flow::FromGamecube => TargettedPacket::Server(pkt.clone()),
+            _ => TargettedPacket::Client(pkt.clone()),
+        };
+
+        replayed.push(Replayed {
+            flow: flow,
+            packet: pkt,
+            emitted: proxy.filter_packet(filters, targetted)?,
+        });
+    }
+
+    Ok(replayed)
+}
+
+// `darkbridge --replay <file>`, prints what the filters did with each packet and where the session ended up
+pub fn run<P: AsRef<Path>>(path: P, mut config: Config) -> Result<(), ReplayError> {
+    let capture = CaptureReader::open(path)?;
+    config.client = capture.version;
+    let mut proxy = Proxy::offline(0, config)?;
+
+    let replayed = replay(&mut proxy, capture, &filters::default_filters())?;
+    for r in replayed.iter() {
+        println!("[replay] {:?} {:?}", r.flow, r.packet);
+        for pkt in r.emitted.iter() {
+            let (target, pkt) = match pkt {
+                TargettedPacket::Client(pkt) => ("gamecube", pkt),
+                TargettedPacket::Server(pkt) => ("server", pkt),
+            };
+            println!("[replay]   -> {}\n{}", target, hexdump(&pkt.as_bytes(proxy.config.client)));
+        }
+    }
+    println!("[replay] {} packets, final state: {:?}", replayed.len(), proxy.gamestate);
+
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::{Recorder, Tap};
+    use crate::packet::ClientVersion;
+
+    fn float_bytes(floats: &[f32]) -> Vec<u8> {
+        floats.iter().flat_map(|f| f.to_le_bytes().to_vec()).collect()
+    }
+
+    #[test]
+    fn replay_updates_gamestate() {
+        let version = ClientVersion::Gamecube;
+        let path = std::env::temp_dir().join(format!("darkbridge-replay-test-{}.cap", std::process::id()));
+        let mut recorder = Recorder::create(&path, 1, version).unwrap();
+
+        // area change then a stop, both from the gamecube
+        recorder.packet(Flow::FromGamecube, &version.frame(0x60, 0, &[0x1F, 0x02, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00])).unwrap();
+        let mut stop = vec![0x3E, 0x06, 0x00, 0x00, 0, 0, 0, 0, 0, 0, 0, 0];
+        stop.extend(float_bytes(&[10.0, 20.0, 30.0]));
+        recorder.packet(Flow::FromGamecube, &version.frame(0x60, 0, &stop)).unwrap();
+        // what the proxy sent at the time is skipped
+        recorder.packet(Flow::ToServer, &version.frame(0x60, 0, &[0x1F, 0x02, 0x00, 0x00, 0x09, 0x00, 0x00, 0x00])).unwrap();
+        // a redirect has nowhere to go offline so it is passed along as is
+        recorder.packet(Flow::FromServer, &version.frame(0x19, 0, &[10, 0, 0, 1, 0x8C, 0x23, 0, 0])).unwrap();
+        drop(recorder);
+
+        let capture = CaptureReader::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let mut config = Config::for_tests(&[]);
+        config.client = version;
+        let mut proxy = Proxy::offline(0, config).unwrap();
+
+        let replayed = replay(&mut proxy, capture, &filters::default_filters()).unwrap();
+        assert_eq!(replayed.len(), 3);
+        assert_eq!(proxy.gamestate.floor, 5);
+        assert_eq!(proxy.gamestate.position.x, 10.0);
+        assert_eq!(proxy.gamestate.position.y, 20.0);
+        assert_eq!(proxy.gamestate.position.z, 30.0);
+
+        match replayed[2].emitted.as_slice() {
+            [TargettedPacket::Client(Packet::Redirect(redirect))] => assert_eq!(redirect.ip, [10, 0, 0, 1]),
+            emitted => panic!("redirect came out as {:?}", emitted),
+        }
+    }
+}
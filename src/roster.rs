@@ -0,0 +1,160 @@
+// everyone in the current lobby or game, by client id. the join packets say who
+// they are, their game commands say where they are
+use std::collections::BTreeMap;
+
+use crate::gamecommand::{GameCommand, GameCommandAction};
+use crate::lobby::PlayerHeader;
+use crate::proxy::Position;
+
+#[derive(Debug, Clone)]
+pub struct Player {
+    pub client_id: u8,
+    // None for someone only seen through their game commands
+    pub header: Option<PlayerHeader>,
+    pub floor: Option<u32>,
+    pub position: Option<Position>,
+}
+
+impl Player {
+    fn new(client_id: u8, header: Option<PlayerHeader>) -> Player {
+        Player {
+            client_id: client_id,
+            header: header,
+            floor: None,
+            position: None,
+        }
+    }
+}
+
+// 1: Kireek (HUcast Redria lv200) floor 3 at (12.0, -40.5)
+impl std::fmt::Display for Player {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}: ", self.client_id)?;
+        match self.header {
+            Some(ref header) => write!(f, "{}", header)?,
+            None => write!(f, "?")?,
+        }
+        if let Some(floor) = self.floor {
+            write!(f, " floor {}", floor)?;
+        }
+        if let Some(position) = self.position {
+            write!(f, " at ({:.1}, {:.1})", position.x, position.z)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Roster {
+    players: BTreeMap<u8, Player>,
+}
+
+impl Roster {
+    pub fn new() -> Roster {
+        Roster {
+            players: BTreeMap::new(),
+        }
+    }
+
+    // we moved somewhere new, whoever was around before isn't anymore
+    pub fn join(&mut self, headers: &[PlayerHeader]) {
+        self.players.clear();
+        self.arrive(headers);
+    }
+
+    pub fn arrive(&mut self, headers: &[PlayerHeader]) {
+        for header in headers {
+            self.players.insert(header.client_id, Player::new(header.client_id, Some(header.clone())));
+        }
+    }
+
+    pub fn leave(&mut self, client_id: u8) {
+        self.players.remove(&client_id);
+    }
+
+    // movement carries the sender's client id, so they get an entry even if we missed
+    // them joining. other subcommands can have an entity id or nothing in that byte
+    pub fn update(&mut self, cmd: &GameCommand) {
+        match cmd.cmd {
+            GameCommandAction::PlayerArea(_) | GameCommandAction::PlayerWalk(_)
+                | GameCommandAction::PlayerRun(_) | GameCommandAction::PlayerStop(_) => {},
+            _ => return,
+        }
+        let player = self.players.entry(cmd.client).or_insert_with(|| Player::new(cmd.client, None));
+        let y = player.position.map(|p| p.y).unwrap_or(0.0);
+        match cmd.cmd {
+            GameCommandAction::PlayerArea(ref area) => {
+                player.floor = Some(area.floor);
+                player.position = None;
+            },
+            GameCommandAction::PlayerWalk(ref walk) => player.position = Some(Position { x: walk.x, y: y, z: walk.z }),
+            GameCommandAction::PlayerRun(ref run) => player.position = Some(Position { x: run.x, y: y, z: run.z }),
+            GameCommandAction::PlayerStop(ref stop) => player.position = Some(Position { x: stop.x, y: stop.y, z: stop.z }),
+            _ => {},
+        }
+    }
+
+    pub fn get(&self, client_id: u8) -> Option<&Player> {
+        self.players.get(&client_id)
+    }
+
+    pub fn players(&self) -> impl Iterator<Item = &Player> {
+        self.players.values()
+    }
+
+    pub fn len(&self) -> usize {
+        self.players.len()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::{Packet, ClientVersion};
+
+    fn header(client_id: u8, name: &str) -> PlayerHeader {
+        PlayerHeader {
+            client_id: client_id,
+            guildcard: 42000000 + client_id as u32,
+            name: name.to_string(),
+            class: Some(0),
+            section_id: Some(0),
+            level: Some(1),
+        }
+    }
+
+    fn game_command(data: Vec<u8>) -> GameCommand {
+        match Packet::parse(ClientVersion::Gamecube, 0x60, 0, &data) {
+            Packet::GameCommand(cmd) => cmd,
+            pkt => panic!("parsed as {:?}", pkt),
+        }
+    }
+
+    #[test]
+    fn follows_game_commands() {
+        let mut roster = Roster::new();
+        roster.join(&[header(0, "Kireek"), header(2, "Ash")]);
+
+        roster.update(&game_command(vec![0x1F, 0x02, 0x02, 0x00, 0x03, 0x00, 0x00, 0x00]));
+        let mut run = vec![0x42, 0x03, 0x02, 0x00];
+        run.extend(12.0f32.to_le_bytes().iter().chain((-40.5f32).to_le_bytes().iter()));
+        roster.update(&game_command(run));
+        assert_eq!(roster.get(2).unwrap().to_string(), "2: Ash (HUmar Viridia lv1) floor 3 at (12.0, -40.5)");
+
+        // someone we never saw join
+        roster.update(&game_command(vec![0x1F, 0x02, 0x03, 0x00, 0x01, 0x00, 0x00, 0x00]));
+        assert_eq!(roster.get(3).unwrap().to_string(), "3: ? floor 1");
+
+        // an item drop's header isn't a client id
+        let mut drop = vec![0x5F, 0x0B, 0x07, 0x00];
+        drop.extend(vec![0u8; 40]);
+        roster.update(&game_command(drop));
+        assert!(roster.get(7).is_none());
+
+        roster.leave(0);
+        assert_eq!(roster.players().map(|p| p.client_id).collect::<Vec<_>>(), vec![2, 3]);
+        roster.join(&[header(1, "Asphodel")]);
+        assert_eq!(roster.len(), 1);
+    }
+}